@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS gif_gifs_category_idx;
+DROP TABLE IF EXISTS gif_gifs;
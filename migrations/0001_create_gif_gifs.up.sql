@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS gif_gifs (
+    id BIGINT PRIMARY KEY,
+    url TEXT NOT NULL,
+    category TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS gif_gifs_category_idx ON gif_gifs (category);
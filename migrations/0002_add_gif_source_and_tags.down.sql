@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS gif_tags_tag_idx;
+DROP TABLE IF EXISTS gif_tags;
+ALTER TABLE gif_gifs DROP COLUMN source;
@@ -0,0 +1,17 @@
+DROP INDEX IF EXISTS gif_tags_tag_idx;
+DROP TABLE IF EXISTS gif_tags;
+
+-- SQLite can only drop a column from 3.35 on, so copy everything else into
+-- a new table instead
+CREATE TABLE gif_gifs_new (
+    id BIGINT PRIMARY KEY,
+    url TEXT NOT NULL,
+    category TEXT NOT NULL
+);
+
+INSERT INTO gif_gifs_new (id, url, category) SELECT id, url, category FROM gif_gifs;
+
+DROP TABLE gif_gifs;
+ALTER TABLE gif_gifs_new RENAME TO gif_gifs;
+
+CREATE INDEX IF NOT EXISTS gif_gifs_category_idx ON gif_gifs (category);
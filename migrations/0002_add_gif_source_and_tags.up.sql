@@ -0,0 +1,9 @@
+ALTER TABLE gif_gifs ADD COLUMN source TEXT;
+
+CREATE TABLE IF NOT EXISTS gif_tags (
+    gif_id BIGINT NOT NULL REFERENCES gif_gifs (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (gif_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS gif_tags_tag_idx ON gif_tags (tag);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS gif_api_keys;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS gif_gifs_category_url_idx;
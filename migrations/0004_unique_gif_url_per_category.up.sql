@@ -0,0 +1,4 @@
+-- Keep the oldest copy of anything that was added twice, then add the
+-- unique index. `Backfill::DedupeGifs` in src/migrate.rs does both, so it
+-- can log the ids it drops first.
+SELECT 1;
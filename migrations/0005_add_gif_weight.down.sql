@@ -0,0 +1 @@
+ALTER TABLE gif_gifs DROP COLUMN weight;
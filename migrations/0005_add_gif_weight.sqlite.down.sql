@@ -0,0 +1,32 @@
+-- SQLite can only drop a column from 3.35 on, so copy everything else into
+-- a new table instead. Dropping gif_gifs would cascade to gif_tags, so the
+-- tags are set aside and put back afterwards.
+CREATE TABLE gif_tags_old AS SELECT gif_id, tag FROM gif_tags;
+DROP TABLE gif_tags;
+
+CREATE TABLE gif_gifs_new (
+    id BIGINT PRIMARY KEY,
+    url TEXT NOT NULL,
+    category TEXT NOT NULL,
+    source TEXT
+);
+
+INSERT INTO gif_gifs_new (id, url, category, source)
+SELECT id, url, category, source FROM gif_gifs;
+
+DROP TABLE gif_gifs;
+ALTER TABLE gif_gifs_new RENAME TO gif_gifs;
+
+CREATE INDEX IF NOT EXISTS gif_gifs_category_idx ON gif_gifs (category);
+CREATE UNIQUE INDEX IF NOT EXISTS gif_gifs_category_url_idx ON gif_gifs (category, url);
+
+CREATE TABLE gif_tags (
+    gif_id BIGINT NOT NULL REFERENCES gif_gifs (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (gif_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS gif_tags_tag_idx ON gif_tags (tag);
+
+INSERT INTO gif_tags (gif_id, tag) SELECT gif_id, tag FROM gif_tags_old;
+DROP TABLE gif_tags_old;
@@ -0,0 +1 @@
+ALTER TABLE gif_gifs ADD COLUMN weight DOUBLE PRECISION NOT NULL DEFAULT 1;
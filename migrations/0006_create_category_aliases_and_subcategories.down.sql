@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS gif_subcategories;
+DROP TABLE IF EXISTS gif_category_aliases;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS gif_category_aliases (
+    alias TEXT PRIMARY KEY,
+    category TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS gif_subcategories (
+    parent TEXT NOT NULL,
+    child TEXT NOT NULL,
+    PRIMARY KEY (parent, child)
+);
@@ -0,0 +1,2 @@
+-- The original spelling of each category is gone, so there's nothing to undo
+SELECT 1;
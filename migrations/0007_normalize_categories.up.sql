@@ -0,0 +1,5 @@
+-- Categories are stored normalized from now on. SQL can't apply the same
+-- unicode normalization the server does, so `Backfill::NormalizeCategories`
+-- in src/migrate.rs rewrites them once this has run. Two gifs can end up
+-- the same in the meantime, so the index comes off until it's done.
+DROP INDEX IF EXISTS gif_gifs_category_url_idx;
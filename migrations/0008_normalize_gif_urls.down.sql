@@ -0,0 +1,2 @@
+-- The original form of each url is gone, so there's nothing to undo
+SELECT 1;
@@ -0,0 +1,5 @@
+-- Urls have been normalized on the way in since 0004, but older rows were
+-- stored as sent. `Backfill::NormalizeUrls` in src/migrate.rs rewrites them
+-- once this has run. Two gifs can end up the same in the meantime, so the
+-- index comes off until it's done.
+DROP INDEX IF EXISTS gif_gifs_category_url_idx;
@@ -0,0 +1,4 @@
+-- Categories are case folded now rather than just lowercased, so `straße`
+-- and `strasse` are the same. `Backfill::NormalizeCategories` rewrites them
+-- again once this has run, with the index off until it's done like 0007.
+DROP INDEX IF EXISTS gif_gifs_category_url_idx;
@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use chrono::Utc;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use warp::{reject, Filter, Rejection};
+
+use crate::store::Store;
+
+/// What an API key is allowed to do. `Admin` implies every other scope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Scope> {
+        match s.trim() {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            other => anyhow::bail!("unknown scope `{}`", other),
+        }
+    }
+}
+
+/// Parses a comma separated scope list like `read,write`.
+pub fn parse_scopes(s: &str) -> anyhow::Result<Vec<Scope>> {
+    s.split(',')
+        .filter(|scope| !scope.trim().is_empty())
+        .map(Scope::from_str)
+        .collect()
+}
+
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A stored API key. Only the hash of the key itself is ever kept.
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// Hex encoded SHA-256 of a raw key, which is what the database stores.
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    format!("gif_{}", hex::encode(bytes))
+}
+
+/// The key in an `Authorization: Bearer <key>` header.
+pub fn bearer(header: &str) -> Option<&str> {
+    header.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// No key was sent, or the one that was isn't known.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl reject::Reject for Unauthorized {}
+
+/// The key is valid but lacks the scope the route needs.
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl reject::Reject for Forbidden {}
+
+/// Requires an `Authorization: Bearer <key>` header whose key has `scope`,
+/// and extracts that key.
+pub fn require(
+    scope: Scope,
+    store: Store,
+) -> impl Filter<Extract = (ApiKey,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || store.clone()))
+        .and_then(move |header: Option<String>, store: Store| async move {
+            let key = header
+                .as_deref()
+                .and_then(bearer)
+                .ok_or_else(|| reject::custom(Unauthorized))?;
+
+            let found = store.api_key(&hash_key(key)).await?;
+            match found {
+                Some(api_key) if api_key.allows(scope) => Ok(api_key),
+                Some(_) => Err(reject::custom(Forbidden)),
+                None => Err(reject::custom(Unauthorized)),
+            }
+        })
+}
+
+/// Makes sure `key` exists with the admin scope, so a fresh deploy has
+/// some way in.
+pub async fn seed_admin(store: &Store, key: &str) -> anyhow::Result<()> {
+    let key_hash = hash_key(key);
+    if store.api_key(&key_hash).await?.is_none() {
+        store
+            .insert_api_key(ApiKey {
+                id: crate::gen_flake().await,
+                name: "ADMIN_API_KEY".into(),
+                key_hash,
+                scopes: vec![Scope::Admin],
+                created_at: Utc::now().timestamp_millis(),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Handles `gif keys [create <name> [scopes] | list | revoke <id>]`.
+pub async fn command(store: &Store, args: &[String]) -> anyhow::Result<()> {
+    match args.first().map(String::as_str) {
+        Some("create") => {
+            let name = args
+                .get(1)
+                .ok_or_else(|| anyhow::anyhow!("usage: gif keys create <name> [scopes]"))?;
+            let scopes = parse_scopes(args.get(2).map_or("write", String::as_str))?;
+            let key = generate_key();
+            let api_key = store
+                .insert_api_key(ApiKey {
+                    id: crate::gen_flake().await,
+                    name: name.clone(),
+                    key_hash: hash_key(&key),
+                    scopes,
+                    created_at: Utc::now().timestamp_millis(),
+                })
+                .await?;
+            println!(
+                "Created key {} ({})",
+                api_key.id,
+                format_scopes(&api_key.scopes)
+            );
+            println!("{}", key);
+            println!("This is the only time the key is shown, so keep it somewhere safe.");
+        }
+        None | Some("list") => {
+            for api_key in store.list_api_keys().await? {
+                println!(
+                    "{}  {}  {}",
+                    api_key.id,
+                    api_key.name,
+                    format_scopes(&api_key.scopes)
+                );
+            }
+        }
+        Some("revoke") => {
+            let id: i64 = args
+                .get(1)
+                .ok_or_else(|| anyhow::anyhow!("usage: gif keys revoke <id>"))?
+                .parse()?;
+            if !store.revoke_api_key(id).await? {
+                anyhow::bail!("no key with id {}", id);
+            }
+            println!("Revoked key {}", id);
+        }
+        Some(other) => anyhow::bail!("unknown keys command `{}`", other),
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::store::Entry;
+
+/// Bags kept before expired ones are swept out. Past this the least
+/// recently used bag makes room for a new one.
+const MAX_BAGS: usize = 10_000;
+
+/// Longest session id a client may send.
+pub const MAX_SESSION: usize = 64;
+
+/// Where a session is in its shuffle of a pool. A cycle visits ids in the
+/// order of `rank(seed, id)`, so the bag stays the same size however many
+/// gifs the pool has.
+struct Bag {
+    seed: u64,
+    /// Rank of the last id drawn this cycle, `None` at the start of one.
+    cursor: Option<u64>,
+    touched: Instant,
+}
+
+impl Bag {
+    fn new(now: Instant) -> Bag {
+        Bag {
+            seed: rand::random(),
+            cursor: None,
+            touched: now,
+        }
+    }
+
+    /// Up to `take` ids that haven't come up this cycle, in the order they
+    /// come up, with their ranks.
+    fn ahead(&self, entries: &[Entry], take: usize) -> Vec<(u64, i64)> {
+        let mut ahead: Vec<(u64, i64)> = entries
+            .iter()
+            .map(|entry| (rank(self.seed, entry.id), entry.id))
+            .filter(|(rank, _)| self.cursor.is_none_or(|cursor| *rank > cursor))
+            .collect();
+        if ahead.len() > take {
+            ahead.select_nth_unstable(take);
+            ahead.truncate(take);
+        }
+        ahead.sort_unstable();
+        ahead
+    }
+}
+
+/// Where `id` comes up in the cycle shuffled by `seed`. SplitMix64's
+/// finalizer is a bijection, so no two ids share a rank.
+fn rank(seed: u64, id: i64) -> u64 {
+    let mut z = (id as u64) ^ seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Shuffle bags per session and pool of gifs, so a session sees every gif
+/// in a category once before any of them comes up again.
+///
+/// Bags only live in this process and are dropped once they haven't been
+/// drawn from for `ttl`.
+pub struct Bags {
+    ttl: Duration,
+    bags: Mutex<HashMap<(String, String), Bag>>,
+}
+
+impl Bags {
+    pub fn new(ttl: Duration) -> Bags {
+        Bags {
+            ttl,
+            bags: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Draws up to `count` distinct ids from `session`'s bag for `pool`,
+    /// usually a category, whose gifs are `entries`. A new cycle starts when
+    /// the bag runs out.
+    ///
+    /// Ids in `exclude` are passed over as if they'd been drawn. Gifs added
+    /// to `entries` mid-cycle may come up in it, and ones no longer in
+    /// `entries` don't.
+    pub fn draw(
+        &self,
+        session: &str,
+        pool: &str,
+        entries: &[Entry],
+        count: usize,
+        exclude: &[i64],
+    ) -> Vec<i64> {
+        let mut picked = Vec::new();
+        if count == 0 {
+            return picked;
+        }
+        let exclude: HashSet<i64> = exclude.iter().copied().collect();
+
+        let now = Instant::now();
+        let mut bags = self.bags.lock().unwrap();
+        let key = (session.to_string(), pool.to_string());
+        if bags.len() >= MAX_BAGS && !bags.contains_key(&key) {
+            self.sweep(&mut bags, now);
+        }
+
+        let bag = bags.entry(key).or_insert_with(|| Bag::new(now));
+        if now.duration_since(bag.touched) >= self.ttl {
+            *bag = Bag::new(now);
+        }
+        bag.touched = now;
+
+        // Only one new cycle, so a category that's all excluded can't spin
+        // forever
+        for cycle in 0..2 {
+            if cycle == 1 {
+                *bag = Bag::new(now);
+            }
+            // Every id passed over is excluded or already picked, so this
+            // many is always enough
+            let take = count + exclude.len() + picked.len();
+            for (rank, id) in bag.ahead(entries, take) {
+                bag.cursor = Some(rank);
+                if exclude.contains(&id) || picked.contains(&id) {
+                    continue;
+                }
+                picked.push(id);
+                if picked.len() == count {
+                    return picked;
+                }
+            }
+        }
+        picked
+    }
+
+    fn sweep(&self, bags: &mut HashMap<(String, String), Bag>, now: Instant) {
+        bags.retain(|_, bag| now.duration_since(bag.touched) < self.ttl);
+        if bags.len() >= MAX_BAGS {
+            let oldest = bags
+                .iter()
+                .min_by_key(|(_, bag)| bag.touched)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                bags.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(ids: impl Iterator<Item = i64>) -> Vec<Entry> {
+        ids.map(|id| Entry { id, weight: 1.0 }).collect()
+    }
+
+    fn bags() -> Bags {
+        Bags::new(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn every_gif_comes_up_once_per_cycle() {
+        let bags = bags();
+        let entries = entries(1..=10);
+        for _ in 0..3 {
+            let mut cycle: Vec<i64> = (0..5)
+                .flat_map(|_| bags.draw("s", "hug", &entries, 2, &[]))
+                .collect();
+            cycle.sort_unstable();
+            assert_eq!(cycle, (1..=10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn sessions_and_pools_have_their_own_bags() {
+        let bags = bags();
+        let entries = entries(1..=10);
+        assert_eq!(bags.draw("a", "hug", &entries, 10, &[]).len(), 10);
+        assert_eq!(bags.draw("b", "hug", &entries, 10, &[]).len(), 10);
+        assert_eq!(bags.draw("a", "pat", &entries, 10, &[]).len(), 10);
+    }
+
+    #[test]
+    fn excluded_ids_are_never_drawn() {
+        let bags = bags();
+        let entries = entries(1..=10);
+        for _ in 0..5 {
+            let picked = bags.draw("s", "hug", &entries, 10, &[2, 5]);
+            assert_eq!(picked.len(), 8);
+            assert!(!picked.contains(&2) && !picked.contains(&5));
+        }
+    }
+
+    #[test]
+    fn ids_no_longer_in_entries_are_dropped() {
+        let bags = bags();
+        bags.draw("s", "hug", &entries(1..=10), 1, &[]);
+
+        let even = entries((1..=10).filter(|id| id % 2 == 0));
+        let mut picked = bags.draw("s", "hug", &even, 10, &[]);
+        picked.sort_unstable();
+        assert_eq!(picked, vec![2, 4, 6, 8, 10]);
+    }
+}
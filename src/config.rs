@@ -0,0 +1,184 @@
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Config file read when `CONFIG_FILE` isn't set, if it exists.
+const DEFAULT_FILE: &str = "gif.toml";
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// Everything the server can be configured with.
+///
+/// Values come from the defaults below, then the TOML config file, then
+/// environment variables (including `.env`), with later sources winning.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database_url: String,
+    pub host: IpAddr,
+    pub port: u16,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    /// Seconds to wait for a database connection before giving up.
+    pub connect_timeout: u64,
+    /// Seconds to wait for in-flight requests after SIGTERM or SIGINT.
+    pub shutdown_timeout: u64,
+    pub log_level: String,
+    pub auto_migrate: bool,
+    pub admin_api_key: Option<String>,
+    pub allowed_hosts: Vec<String>,
+    /// Requests per minute each client may make to read routes, 0 for no limit.
+    pub rate_limit_read: u32,
+    /// Requests per minute each client may make to write routes, 0 for no limit.
+    pub rate_limit_write: u32,
+    /// Trust `X-Forwarded-For` for the client ip. Only turn this on behind a proxy.
+    pub trust_proxy: bool,
+    /// Seconds a category's cached id list is trusted for random picks.
+    pub cache_ttl: u64,
+    /// Seconds an unused `?session=` shuffle bag is kept.
+    pub bag_ttl: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            database_url: String::new(),
+            host: IpAddr::from([127, 0, 0, 1]),
+            port: 3030,
+            max_connections: 20,
+            min_connections: 0,
+            connect_timeout: 30,
+            shutdown_timeout: 30,
+            log_level: "info".into(),
+            auto_migrate: true,
+            admin_api_key: None,
+            allowed_hosts: Vec::new(),
+            rate_limit_read: 600,
+            rate_limit_write: 60,
+            trust_proxy: false,
+            cache_ttl: 60,
+            bag_ttl: 3600,
+        }
+    }
+}
+
+impl Config {
+    /// Loads and checks the config, failing on the first bad value.
+    pub fn load() -> anyhow::Result<Config> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_FILE))?
+            }
+            Err(_) => Config::default(),
+        };
+
+        override_from_env("DATABASE_URL", &mut config.database_url)?;
+        override_from_env("HOST", &mut config.host)?;
+        override_from_env("PORT", &mut config.port)?;
+        override_from_env("MAX_CONNECTIONS", &mut config.max_connections)?;
+        override_from_env("MIN_CONNECTIONS", &mut config.min_connections)?;
+        override_from_env("CONNECT_TIMEOUT", &mut config.connect_timeout)?;
+        override_from_env("SHUTDOWN_TIMEOUT", &mut config.shutdown_timeout)?;
+        override_from_env("LOG_LEVEL", &mut config.log_level)?;
+        override_from_env("AUTO_MIGRATE", &mut config.auto_migrate)?;
+        override_from_env("RATE_LIMIT_READ", &mut config.rate_limit_read)?;
+        override_from_env("RATE_LIMIT_WRITE", &mut config.rate_limit_write)?;
+        override_from_env("TRUST_PROXY", &mut config.trust_proxy)?;
+        override_from_env("CACHE_TTL", &mut config.cache_ttl)?;
+        override_from_env("BAG_TTL", &mut config.bag_ttl)?;
+        if let Some(key) = env_value("ADMIN_API_KEY") {
+            config.admin_api_key = Some(key);
+        }
+        if let Some(hosts) = env_value("ALLOWED_HOSTS") {
+            config.allowed_hosts = hosts.split(',').map(String::from).collect();
+        }
+
+        config.allowed_hosts = config
+            .allowed_hosts
+            .iter()
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        config.log_level = config.log_level.trim().to_lowercase();
+
+        config.check()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.database_url.is_empty() {
+            anyhow::bail!("DATABASE_URL is not set");
+        }
+        if self.max_connections == 0 {
+            anyhow::bail!("MAX_CONNECTIONS must be at least 1");
+        }
+        if self.min_connections > self.max_connections {
+            anyhow::bail!(
+                "MIN_CONNECTIONS ({}) can't be more than MAX_CONNECTIONS ({})",
+                self.min_connections,
+                self.max_connections
+            );
+        }
+        if self.connect_timeout == 0 {
+            anyhow::bail!("CONNECT_TIMEOUT must be at least 1 second");
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            anyhow::bail!(
+                "invalid LOG_LEVEL `{}`, expected one of {}",
+                self.log_level,
+                LOG_LEVELS.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl)
+    }
+
+    pub fn bag_ttl(&self) -> Duration {
+        Duration::from_secs(self.bag_ttl)
+    }
+}
+
+/// The env var `name`, treating an empty value the same as an unset one so
+/// `KEY=` in `.env` doesn't wipe out the config file's value.
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|raw| !raw.trim().is_empty())
+}
+
+fn override_from_env<T>(name: &str, value: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(raw) = env_value(name) {
+        *value = raw
+            .trim()
+            .parse()
+            .map_err(|err| anyhow::anyhow!("invalid {} `{}`: {}", name, raw, err))?;
+    }
+    Ok(())
+}
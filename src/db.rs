@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+
+use crate::config::Config;
+use crate::store::{PgStore, SqliteStore, Store};
+
+#[derive(Serialize, Debug)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: u32,
+}
+
+/// A connection pool for whichever database `DATABASE_URL` points at.
+#[derive(Clone, Debug)]
+pub enum Db {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+impl Db {
+    /// Connects to `config.database_url`, picking the backend from its
+    /// scheme (`postgres://` / `postgresql://` or `sqlite:`).
+    pub async fn connect(config: &Config) -> anyhow::Result<Db> {
+        let url = &config.database_url;
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            let pool = PgPoolOptions::new()
+                .max_connections(config.max_connections)
+                .min_connections(config.min_connections)
+                .connect_timeout(config.connect_timeout())
+                .connect(url)
+                .await?;
+            Ok(Db::Postgres(pool))
+        } else if url.starts_with("sqlite:") {
+            let pool = SqlitePoolOptions::new()
+                .max_connections(config.max_connections)
+                .min_connections(config.min_connections)
+                .connect_timeout(config.connect_timeout())
+                .connect(url)
+                .await?;
+            Ok(Db::Sqlite(pool))
+        } else {
+            anyhow::bail!("DATABASE_URL must start with postgres:// or sqlite:")
+        }
+    }
+
+    /// Wraps the pool in the matching `GifStore`.
+    pub fn store(&self) -> Store {
+        match self {
+            Db::Postgres(pool) => Arc::new(PgStore::new(pool.clone())),
+            Db::Sqlite(pool) => Arc::new(SqliteStore::new(pool.clone())),
+        }
+    }
+    /// Runs a trivial query to check the database answers.
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        match self {
+            Db::Postgres(pool) => sqlx::query("select 1").execute(pool).await.map(|_| ()),
+            Db::Sqlite(pool) => sqlx::query("select 1").execute(pool).await.map(|_| ()),
+        }
+    }
+
+    /// Open connections in the pool, and how many of those are idle.
+    pub fn stats(&self) -> PoolStats {
+        // This sqlx only shows these through `Debug`
+        let debug = match self {
+            Db::Postgres(pool) => format!("{:?}", pool),
+            Db::Sqlite(pool) => format!("{:?}", pool),
+        };
+        PoolStats {
+            size: debug_field(&debug, "size"),
+            idle: debug_field(&debug, "num_idle"),
+        }
+    }
+
+    /// Closes every connection, waiting for any that are checked out to
+    /// come back first.
+    pub async fn close(&self) {
+        match self {
+            Db::Postgres(pool) => pool.close().await,
+            Db::Sqlite(pool) => pool.close().await,
+        }
+    }
+}
+
+/// Reads the number after `name: ` in a `Debug` rendering, or 0.
+fn debug_field(debug: &str, name: &str) -> u32 {
+    let field = format!("{}: ", name);
+    debug
+        .find(&field)
+        .map(|at| &debug[at + field.len()..])
+        .and_then(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..end].parse().ok()
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+
+use sqlx::error::DatabaseError;
+use warp::http::StatusCode;
+use warp::reject::{self, Reject};
+use warp::Rejection;
+
+/// A failure talking to the database, sorted by what the client should
+/// make of it.
+#[derive(Debug)]
+pub enum Error {
+    /// The database can't be reached right now, or every connection in the
+    /// pool is busy. Worth retrying later.
+    Unavailable(sqlx::Error),
+    /// The write clashed with a uniqueness or other constraint.
+    Conflict(sqlx::Error),
+    /// Anything else.
+    Database(sqlx::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine readable code for `ErrorMessage`. Clients match on these, so
+    /// they must not change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Unavailable(_) => "DATABASE_UNAVAILABLE",
+            Error::Conflict(_) => "CONFLICT",
+            Error::Database(_) => "DATABASE_ERROR",
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Error {
+        match &err {
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => {
+                Error::Unavailable(err)
+            }
+            sqlx::Error::Database(db) => match class_of(&**db) {
+                Class::Busy => Error::Unavailable(err),
+                Class::Constraint => Error::Conflict(err),
+                Class::Other => Error::Database(err),
+            },
+            _ => Error::Database(err),
+        }
+    }
+}
+
+/// What a database error code says about the failure.
+#[derive(Debug, PartialEq)]
+enum Class {
+    Busy,
+    Constraint,
+    Other,
+}
+
+// Postgres always reports a five character SQLSTATE. SQLite reports a
+// numeric extended result code, all of which are under 10000, whose low byte
+// is the primary result code. Telling them apart by length keeps a numeric
+// SQLSTATE like 42501 from being read as a SQLite code.
+fn classify(code: &str) -> Class {
+    if code.len() == 5 {
+        return match code {
+            // Class 23 is "integrity constraint violation"
+            _ if code.starts_with("23") => Class::Constraint,
+            // Class 08 is "connection exception", class 53 "insufficient
+            // resources" (too many connections and the like)
+            _ if code.starts_with("08") || code.starts_with("53") => Class::Busy,
+            // The server is shutting down, crashed or is still starting
+            "57P01" | "57P02" | "57P03" => Class::Busy,
+            _ => Class::Other,
+        };
+    }
+    match code.parse::<i32>().map(|code| code & 0xff) {
+        // SQLITE_CONSTRAINT
+        Ok(19) => Class::Constraint,
+        // SQLITE_BUSY and SQLITE_LOCKED
+        Ok(5) | Ok(6) => Class::Busy,
+        _ => Class::Other,
+    }
+}
+
+fn class_of(err: &dyn DatabaseError) -> Class {
+    err.code().map_or(Class::Other, |code| classify(&code))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unavailable(err) | Error::Conflict(err) | Error::Database(err) => {
+                write!(f, "{}: {}", self.code(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Reject for Error {}
+
+impl From<Error> for Rejection {
+    fn from(err: Error) -> Rejection {
+        reject::custom(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postgres_codes_are_read_as_sqlstates() {
+        assert_eq!(classify("23505"), Class::Constraint);
+        assert_eq!(classify("08006"), Class::Busy);
+        assert_eq!(classify("53300"), Class::Busy);
+        assert_eq!(classify("57P01"), Class::Busy);
+        assert_eq!(classify("57P03"), Class::Busy);
+        // Its low byte would be SQLITE_BUSY if read as a number
+        assert_eq!(classify("42501"), Class::Other);
+        assert_eq!(classify("42P01"), Class::Other);
+    }
+
+    #[test]
+    fn sqlite_codes_are_read_by_primary_code() {
+        assert_eq!(classify("19"), Class::Constraint);
+        // SQLITE_CONSTRAINT_UNIQUE and SQLITE_CONSTRAINT_PRIMARYKEY
+        assert_eq!(classify("2067"), Class::Constraint);
+        assert_eq!(classify("1555"), Class::Constraint);
+        assert_eq!(classify("5"), Class::Busy);
+        // SQLITE_LOCKED_SHAREDCACHE
+        assert_eq!(classify("262"), Class::Busy);
+        assert_eq!(classify("1"), Class::Other);
+    }
+}
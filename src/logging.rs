@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::time::Instant;
+
+use tracing_subscriber::EnvFilter;
+use url::Url;
+use warp::http::header::{HeaderMap, HeaderValue};
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::{Filter, Reply};
+
+/// Header a request id is read from and echoed back in.
+const REQUEST_ID: &str = "x-request-id";
+
+/// Longest request id accepted from a client before we make our own.
+const MAX_REQUEST_ID: usize = 64;
+
+thread_local! {
+    // Hands the id `requests` picked to the span it opens around the routes.
+    // warp creates the span in the same poll, straight after the id is
+    // picked, so no other request can run on this thread in between.
+    static NEXT_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Logs one JSON object per line to stdout, at `level` or above.
+pub fn init(level: &str) {
+    tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        // The access log already says everything warp's own request lines do
+        .with_env_filter(EnvFilter::new(format!(
+            "{},warp::filters::trace=off",
+            level
+        )))
+        .init();
+}
+
+/// `url` with any password swapped out, so connection strings can be logged.
+pub fn redact(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            if url.password().is_some() {
+                // Only fails for urls without a host, which can't have a password
+                url.set_password(Some("redacted")).ok();
+            }
+            url.to_string()
+        }
+        // Don't guess where the secret is in something that isn't a url
+        Err(_) => "<unparseable url>".into(),
+    }
+}
+
+/// The client's `X-Request-Id` if it's sane, otherwise a fresh one.
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+/// Wraps `filter` so every request gets an id, returned in `X-Request-Id`,
+/// and one access log line with its method, path, status and latency.
+///
+/// `filter` runs inside a `request` span carrying the id, so anything it
+/// logs can be matched up with the access log line.
+pub fn requests<F, R>(
+    filter: F,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned().map(|headers: HeaderMap| {
+            let id = request_id(&headers);
+            NEXT_ID.with(|next| *next.borrow_mut() = Some(id.clone()));
+            id
+        }))
+        .and(filter.with(warp::trace(|_| {
+            let id = NEXT_ID
+                .with(|next| next.borrow_mut().take())
+                .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+            // Error level, so the span is kept at any LOG_LEVEL that logs at all
+            tracing::error_span!("request", request_id = %id)
+        })))
+        .map(finish)
+}
+
+/// Logs the access line for a request and puts its id on the response.
+fn finish<T: Reply>(
+    start: Instant,
+    method: Method,
+    path: FullPath,
+    id: String,
+    reply: T,
+) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    tracing::info!(
+        target: "gif::access",
+        request_id = %id,
+        method = %method,
+        path = path.as_str(),
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        "request"
+    );
+    // The id is made of header-safe characters either way
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
@@ -1,72 +1,534 @@
-use dotenv;
-
 use std::convert::Infallible;
 use std::env;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use warp::http::StatusCode;
+use tokio::sync::oneshot;
+
+use warp::http::{HeaderValue, StatusCode};
 use warp::{reject, Filter, Rejection, Reply};
 
-use serde;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::{PgPool, PgPoolOptions};
 
 
 use chrono::Local;
 use chrono_humanize::HumanTime;
+use lazy_static::lazy_static;
 
 use rustflake::Snowflake;
 
+mod auth;
+mod bags;
+mod config;
+mod db;
+mod error;
+mod logging;
+mod metrics;
+mod migrate;
+mod normalize;
+mod ratelimit;
+mod store;
+mod validate;
+
+use auth::{ApiKey, Scope};
+use bags::Bags;
+use config::Config;
+use db::Db;
+use normalize::Decoded;
+use ratelimit::{Class, RateLimiter};
+use store::{CachedStore, Match, MemoryStore, Mode, Store};
+use validate::UrlRules;
+
+/// One page of `GET /api/gifs`. Pass `next` back as `after` to get the
+/// page after it; it's `null` on the last page.
 #[derive(Deserialize, Serialize, Debug)]
 struct Gifs {
-    gif: Vec<Gif>,
+    gifs: Vec<Gif>,
+    next: Option<i64>,
+}
+
+/// Body of `GET /readyz`.
+#[derive(Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+    database: &'static str,
+    pending_migrations: usize,
+    pool: Option<db::PoolStats>,
+}
+
+/// How long `/readyz` waits on the database before calling it down.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Query string for `GET /api/gif/:category`.
+#[derive(Deserialize, Serialize, Debug)]
+struct RandomQuery {
+    /// Return an array of up to this many distinct gifs instead of one gif.
+    count: Option<u32>,
+    /// Comma separated ids to leave out.
+    exclude: Option<String>,
+    /// `uniform` (the default) or `weighted`.
+    mode: Option<Mode>,
+    /// Draw from this session's shuffle bag instead, so nothing repeats
+    /// until the whole category has come up.
+    session: Option<String>,
+    /// Comma separated tags the gif has to have.
+    tags: Option<String>,
+    /// Whether `tags` all have to match (the default) or any of them.
+    #[serde(rename = "match")]
+    matching: Option<Match>,
 }
 
+/// Query string for `GET /api/gifs`.
 #[derive(Deserialize, Serialize, Debug)]
-struct Gif {
+struct ListQuery {
+    after: Option<i64>,
+    category: Option<String>,
+    url: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Gif {
     id: i64,
     url: String,
     category: String,
+    source: Option<String>,
+    /// Relative odds of being picked by `?mode=weighted`.
+    weight: f64,
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
+pub struct CategoryCount {
+    category: String,
+    count: i64,
+}
+
+/// Another name for `category`, e.g. `hugs` for `hug`.
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Alias {
+    alias: String,
+    category: String,
 }
 
+/// `child` is part of `parent`, so random gifs from `parent` can come
+/// from `child` too.
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Subcategory {
+    parent: String,
+    child: String,
+}
+
+/// Request body for `PUT /api/aliases/:alias`.
 #[derive(Deserialize, Serialize, Debug)]
-struct UrlQuery {
+struct AliasTarget {
+    category: String,
+}
+
+/// Request body for `POST /api/gifs`.
+#[derive(Deserialize, Serialize, Debug)]
+struct NewGif {
     url: String,
+    category: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    source: Option<String>,
+    weight: Option<f64>,
 }
 
+/// Request body for `PATCH /api/gifs/:id`. Fields left out stay as they are.
 #[derive(Deserialize, Serialize, Debug)]
-struct Id {
-    id: i64,
+pub struct GifPatch {
+    url: Option<String>,
+    category: Option<String>,
+    weight: Option<f64>,
+    /// Replaces every tag the gif has.
+    tags: Option<Vec<String>>,
 }
 
+/// The gif that was asked for doesn't exist.
+///
+/// Handlers use this instead of `reject::not_found()`, which warp drops in
+/// favour of any other rejection, e.g. `METHOD_NOT_ALLOWED` from a sibling
+/// route on the same path.
+#[derive(Debug)]
+struct NotFound;
+
+impl reject::Reject for NotFound {}
+
+/// A query parameter was present but out of range or malformed.
+#[derive(Debug)]
+struct BadRequest;
+
+impl reject::Reject for BadRequest {}
+
+/// Most gifs `?count=` can ask for at once.
+const MAX_COUNT: u32 = 50;
+
+/// Most ids `?exclude=` can list.
+const MAX_EXCLUDE: usize = 500;
+
+/// Most tags `?tags=` can list.
+const MAX_TAGS: usize = 20;
+
+/// Heaviest a gif can be made. Weights run from 0 up to this.
+const MAX_WEIGHT: f64 = 1000.0;
+
+/// How many times a random pick tops up after finding some of its ids gone.
+const MAX_DRAWS: u32 = 3;
+
+/// How many fresh ids a submission tries before giving up on a clash.
+const MAX_ID_ATTEMPTS: u32 = 3;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
-    let pool = get_pool().await?;
-    let with_db = warp::any().map(move || pool.clone());
+    let config = Config::load()?;
+    logging::init(&config.log_level);
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (store, pool): (Store, Option<Db>) = if config.database_url == "memory:" {
+        (Arc::new(MemoryStore::new()), None)
+    } else {
+        let pool = get_pool(&config).await?;
+
+        if args.first().map(String::as_str) == Some("migrate") {
+            return migrate::command(&pool, &args[1..]).await;
+        }
+
+        // Set AUTO_MIGRATE=false to only apply migrations through `gif migrate`
+        if config.auto_migrate {
+            migrate::up(&pool).await?;
+        }
+
+        if args.first().map(String::as_str) == Some("keys") {
+            return auth::command(&pool.store(), &args[1..]).await;
+        }
+
+        (pool.store(), Some(pool))
+    };
+    let store: Store = Arc::new(CachedStore::new(store, config.cache_ttl()));
+
+    if let Some(key) = &config.admin_api_key {
+        auth::seed_admin(&store, key).await?;
+    }
+
+    let url_rules = Arc::new(UrlRules {
+        allowed_hosts: config.allowed_hosts.clone(),
+    });
+
+    let limiter = Arc::new(RateLimiter::new(
+        config.rate_limit_read,
+        config.rate_limit_write,
+        config.trust_proxy,
+    ));
+
+    let bags = Arc::new(Bags::new(config.bag_ttl()));
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (addr, server) = warp::serve(routes(store, url_rules, limiter, bags, pool.clone()))
+        .try_bind_with_graceful_shutdown((config.host, config.port), async {
+            stopped.await.ok();
+        })
+        .map_err(|err| {
+            anyhow::anyhow!(
+                "couldn't listen on {}:{}: {}",
+                config.host,
+                config.port,
+                err
+            )
+        })?;
+    let server = tokio::spawn(server);
+    tracing::info!(%addr, "listening");
+
+    shutdown_signal().await;
+    tracing::info!(
+        timeout_secs = config.shutdown_timeout,
+        "shutting down, waiting for requests to finish"
+    );
+    stop.send(()).ok();
+    // Closing the pool waits for connections held by requests, so it has to
+    // fit in the same window or a hung query would block shutdown forever
+    let deadline = tokio::time::Instant::now() + config.shutdown_timeout();
+    if tokio::time::timeout_at(deadline, server).await.is_err() {
+        tracing::warn!("gave up waiting for requests to finish");
+    }
+
+    if let Some(pool) = pool {
+        if tokio::time::timeout_at(deadline, pool.close())
+            .await
+            .is_err()
+        {
+            tracing::warn!("gave up waiting for database connections to close");
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(err) => {
+                tracing::warn!(
+                    error = %err,
+                    "couldn't listen for SIGTERM, only SIGINT will stop the server"
+                );
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Every route the server answers, with rejections already recovered.
+fn routes(
+    store: Store,
+    url_rules: Arc<UrlRules>,
+    limiter: Arc<RateLimiter>,
+    bags: Arc<Bags>,
+    db: Option<Db>,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+    // Liveness only says the process can answer, so it never touches the database
+    let healthz =
+        warp::path!("healthz").map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+
+    let readyz = warp::path!("readyz")
+        .and(with_db(db.clone()))
+        .and_then(|db: Option<Db>| readyz(db));
+
+    let metrics = warp::path!("metrics")
+        .and(with_db(db))
+        .map(|db: Option<Db>| metrics::render(db.as_ref()));
 
     // Match `/:Seconds`...
     let wait = warp::path::param()
         // and_then create a `Future` that will simply wait N seconds...
-        .and_then(|seconds| sleepy(seconds));
+        .and_then(sleepy);
+
+    let stringy = warp::path!("re" / Decoded).map(|Decoded(string)| string);
+
+    let random_gif = warp::path!("api" / "gif" / Decoded)
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(warp::query::<RandomQuery>())
+        .and(with_store(store.clone()))
+        .and(with_bags(bags))
+        .and_then(
+            |Decoded(cat), query: RandomQuery, store: Store, bags: Arc<Bags>| {
+                get_gifs(cat, query, store, bags)
+            },
+        );
+
+    let gif_by_id = warp::path!("api" / "gifs" / i64)
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(with_store(store.clone()))
+        .and_then(|id, store: Store| get_gif(id, store));
+
+    let list_gifs = warp::path!("api" / "gifs")
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(warp::query::<ListQuery>())
+        .and(with_store(store.clone()))
+        .and_then(|query: ListQuery, store: Store| list_gifs(query, store));
+
+    let categories = warp::path!("api" / "categories")
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(with_store(store.clone()))
+        .and_then(|store: Store| get_categories(store));
+
+    let aliases = warp::path!("api" / "aliases")
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(with_store(store.clone()))
+        .and_then(|store: Store| get_aliases(store));
+
+    let subcategories = warp::path!("api" / "subcategories")
+        .and(ratelimit::limit(
+            Class::Read,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(with_store(store.clone()))
+        .and_then(|store: Store| get_subcategories(store));
+
+    let read_api = random_gif
+        .or(gif_by_id)
+        .or(list_gifs)
+        .or(categories)
+        .or(aliases)
+        .or(subcategories);
 
-    let stringy = warp::path!("re" / String).map(|string: String| string.replace("%20", " "));
+    let add_gif = warp::post()
+        .and(warp::path!("api" / "gifs"))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Write, store.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_store(store.clone()))
+        .and(with_url_rules(url_rules.clone()))
+        .and_then(
+            |_key: ApiKey, new: NewGif, store: Store, rules: Arc<UrlRules>| {
+                post_gifs(new, store, rules)
+            },
+        );
 
-    let random_gif = warp::path!("api" / "gif" / String)
-        .and(with_db.clone())
-        .and_then(|cat, postgres: PgPool| get_gifs(cat, postgres));
+    let update_gif = warp::patch()
+        .and(warp::path!("api" / "gifs" / i64))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Write, store.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_store(store.clone()))
+        .and(with_url_rules(url_rules))
+        .and_then(
+            |id, _key: ApiKey, patch: GifPatch, store: Store, rules: Arc<UrlRules>| {
+                patch_gif(id, patch, store, rules)
+            },
+        );
 
-    let add_gif = warp::path!("api" / "gif" / String)
-        .and(warp::query::<UrlQuery>())
-        .and(with_db.clone())
-        .and_then(|cat, url: UrlQuery, postgres: PgPool| post_gifs(url.url, cat, postgres));
+    let delete_gif = warp::delete()
+        .and(warp::path!("api" / "gifs" / i64))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Write, store.clone()))
+        .and(with_store(store.clone()))
+        .and_then(|id, _key: ApiKey, store: Store| delete_gif(id, store));
+
+    let set_alias = warp::put()
+        .and(warp::path!("api" / "aliases" / Decoded))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Admin, store.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_store(store.clone()))
+        .and_then(
+            |Decoded(alias), _key: ApiKey, target: AliasTarget, store: Store| {
+                put_alias(alias, target, store)
+            },
+        );
+
+    let unset_alias = warp::delete()
+        .and(warp::path!("api" / "aliases" / Decoded))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Admin, store.clone()))
+        .and(with_store(store.clone()))
+        .and_then(|Decoded(alias), _key: ApiKey, store: Store| delete_alias(alias, store));
+
+    let add_subcategory = warp::put()
+        .and(warp::path!(
+            "api" / "categories" / Decoded / "children" / Decoded
+        ))
+        .and(ratelimit::limit(
+            Class::Write,
+            limiter.clone(),
+            store.clone(),
+        ))
+        .and(auth::require(Scope::Admin, store.clone()))
+        .and(with_store(store.clone()))
+        .and_then(
+            |Decoded(parent), Decoded(child), _key: ApiKey, store: Store| {
+                put_subcategory(parent, child, store)
+            },
+        );
+
+    let remove_subcategory = warp::delete()
+        .and(warp::path!(
+            "api" / "categories" / Decoded / "children" / Decoded
+        ))
+        .and(ratelimit::limit(Class::Write, limiter, store.clone()))
+        .and(auth::require(Scope::Admin, store.clone()))
+        .and(with_store(store.clone()))
+        .and_then(
+            |Decoded(parent), Decoded(child), _key: ApiKey, store: Store| {
+                delete_subcategory(parent, child, store)
+            },
+        );
 
     let routes = warp::get()
-        .and(wait.or(stringy).or(add_gif).or(random_gif))
+        .and(
+            healthz
+                .or(readyz)
+                .or(metrics)
+                .or(wait)
+                .or(stringy)
+                .or(read_api),
+        )
+        .or(add_gif)
+        .or(update_gif)
+        .or(delete_gif)
+        .or(set_alias)
+        .or(unset_alias)
+        .or(add_subcategory)
+        .or(remove_subcategory)
         .recover(handle_rejection);
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    Ok(())
+    logging::requests(routes).with(warp::log::custom(metrics::record))
+}
+
+fn with_store(store: Store) -> impl Filter<Extract = (Store,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+fn with_db(db: Option<Db>) -> impl Filter<Extract = (Option<Db>,), Error = Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+fn with_bags(bags: Arc<Bags>) -> impl Filter<Extract = (Arc<Bags>,), Error = Infallible> + Clone {
+    warp::any().map(move || bags.clone())
+}
+
+fn with_url_rules(
+    rules: Arc<UrlRules>,
+) -> impl Filter<Extract = (Arc<UrlRules>,), Error = Infallible> + Clone {
+    warp::any().map(move || rules.clone())
 }
 
 async fn sleepy(seconds: u8) -> Result<impl warp::Reply, Infallible> {
@@ -78,64 +540,418 @@ let ht = HumanTime::from(dt);
     Ok(format!("I waited {} seconds!", ht))
 }
 
-pub async fn get_pool() -> anyhow::Result<PgPool, anyhow::Error> {
-    let pool = PgPoolOptions::new()
-        .max_connections(20)
-        .connect(&env::var("DATABASE_URL")?)
-        .await?;
-    println!(
-        "Connected to the database at url {}",
-        &env::var("DATABASE_URL")?
+/// Ready once the database answers and every migration has been applied.
+/// The memory store is always ready.
+async fn readyz(db: Option<Db>) -> Result<impl Reply, Infallible> {
+    let mut readiness = Readiness {
+        ready: true,
+        database: "ok",
+        pending_migrations: 0,
+        pool: db.as_ref().map(Db::stats),
+    };
+
+    if let Some(db) = &db {
+        let checks = async {
+            db.ping().await?;
+            migrate::pending(db).await
+        };
+        match tokio::time::timeout(READY_TIMEOUT, checks).await {
+            Ok(Ok(pending)) => readiness.pending_migrations = pending.len(),
+            Ok(Err(err)) => {
+                tracing::warn!(error = %err, "readiness check failed");
+                readiness.database = "down";
+            }
+            Err(_) => readiness.database = "timeout",
+        }
+        readiness.ready = readiness.database == "ok" && readiness.pending_migrations == 0;
+    }
+
+    let code = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        code,
+    ))
+}
+
+pub async fn get_pool(config: &Config) -> anyhow::Result<Db, anyhow::Error> {
+    let db = Db::connect(config).await?;
+    tracing::info!(
+        url = %logging::redact(&config.database_url),
+        "connected to the database"
     );
-    Ok(pool)
-}
-
-async fn get_gifs(cat: String, pool: PgPool) -> Result<impl Reply, Rejection> {
-    let gifs = sqlx::query_as!(
-        Gif,
-        "
-        select id, url, category
-        from gif_gifs 
-        WHERE category = $1
-        order by random() 
-        limit 1
-        ",
-        cat
-    )
-    .fetch_all(&pool)
-    .await
-    .unwrap();
-
-    if gifs.len() == 0 {
-        return Err(reject::not_found());
-    }
-
-    Ok(warp::reply::json(&gifs[0]))
-}
-
-async fn post_gifs(url: String, cat: String, pool: PgPool) -> Result<impl Reply, Rejection> {
-    let gifs = sqlx::query_as!(
-        Id,
-        "
-        INSERT INTO public.gif_gifs (id, url, category) 
-        VALUES ($1, $2, $3)
-        returning id;
-        ",
-        gen_flake().await,
-        url,
-        cat
-    )
-    .fetch_all(&pool)
-    .await
-    .unwrap();
-
-    Ok(warp::reply::json(&gifs))
+    Ok(db)
 }
 
-async fn gen_flake() -> i64 {
+async fn get_gifs(
+    cat: String,
+    query: RandomQuery,
+    store: Store,
+    bags: Arc<Bags>,
+) -> Result<impl Reply, Rejection> {
+    let cat = normalize::category(&cat);
+
+    let count = match query.count {
+        Some(count) if !(1..=MAX_COUNT).contains(&count) => return Err(reject::custom(BadRequest)),
+        count => count,
+    };
+    let exclude = match &query.exclude {
+        Some(exclude) => parse_ids(exclude).ok_or_else(|| reject::custom(BadRequest))?,
+        None => Vec::new(),
+    };
+
+    let tags = match &query.tags {
+        Some(tags) => {
+            let tags = clean_tags(tags.split(','));
+            if tags.is_empty() || tags.len() > MAX_TAGS {
+                return Err(reject::custom(BadRequest));
+            }
+            Some(tags)
+        }
+        None => None,
+    };
+    let all = query.matching.unwrap_or(Match::All) == Match::All;
+
+    // Aliases and subcategories decide which categories this draws from
+    let categories = store.resolve(&cat).await?;
+    let mut entries = match categories.as_slice() {
+        [category] => store.entries(category).await?,
+        _ => {
+            let mut merged = Vec::new();
+            for category in &categories {
+                merged.extend(store.entries(category).await?.iter().copied());
+            }
+            merged.sort_by_key(|entry| entry.id);
+            Arc::new(merged)
+        }
+    };
+    if let Some(tags) = &tags {
+        let mut tagged = Vec::new();
+        for category in &categories {
+            tagged.extend(store.tagged(category, tags, all).await?);
+        }
+        tagged.sort_unstable();
+        entries = Arc::new(
+            entries
+                .iter()
+                .copied()
+                .filter(|entry| tagged.binary_search(&entry.id).is_ok())
+                .collect(),
+        );
+    }
+
+    // Aliases share a bag, but a tag filter draws from a different set of
+    // gifs, so it gets its own
+    let bag = match &query.session {
+        Some(session) => {
+            if !valid_session(session) || query.mode.is_some() {
+                return Err(reject::custom(BadRequest));
+            }
+            let pool = match &tags {
+                Some(tags) => format!("{}?tags={}&all={}", categories[0], tags.join(","), all),
+                None => categories[0].clone(),
+            };
+            Some((session, pool))
+        }
+        None => None,
+    };
+
+    let wanted = count.unwrap_or(1) as usize;
+    let mut exclude = exclude;
+    let mut gifs = Vec::new();
+    // Ids cached a while ago can outlive their gif, so when some picks are
+    // gone, forget them and top up from what's left
+    for _ in 0..MAX_DRAWS {
+        let want = wanted - gifs.len();
+        let picked = match &bag {
+            Some((session, pool)) => bags.draw(session, pool, &entries, want, &exclude),
+            None => store::pick(
+                &entries,
+                want,
+                &exclude,
+                query.mode.unwrap_or(Mode::Uniform),
+            ),
+        };
+        if picked.is_empty() {
+            break;
+        }
+
+        let found = store.get_ordered(&picked).await?;
+        let missing: Vec<i64> = picked
+            .iter()
+            .copied()
+            .filter(|id| !found.iter().any(|gif| gif.id == *id))
+            .collect();
+        exclude.extend(found.iter().map(|gif| gif.id));
+        gifs.extend(found);
+        if missing.is_empty() {
+            break;
+        }
+        store.forget(&missing);
+        entries = Arc::new(
+            entries
+                .iter()
+                .copied()
+                .filter(|entry| !missing.contains(&entry.id))
+                .collect(),
+        );
+    }
+
+    if gifs.is_empty() {
+        return Err(reject::custom(NotFound));
+    }
+
+    // Without `?count=` the reply stays a single gif, like it always was
+    match count {
+        Some(_) => Ok(warp::reply::json(&gifs)),
+        None => Ok(warp::reply::json(&gifs[0])),
+    }
+}
+
+fn valid_session(session: &str) -> bool {
+    !session.is_empty()
+        && session.len() <= bags::MAX_SESSION
+        && session
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parses a comma separated id list, ignoring empty entries.
+fn parse_ids(ids: &str) -> Option<Vec<i64>> {
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    if ids.len() > MAX_EXCLUDE {
+        return None;
+    }
+    Some(ids)
+}
+
+async fn get_gif(id: i64, store: Store) -> Result<impl Reply, Rejection> {
+    match store.get(id).await? {
+        Some(gif) => Ok(warp::reply::json(&gif)),
+        None => Err(reject::custom(NotFound)),
+    }
+}
+
+async fn list_gifs(query: ListQuery, store: Store) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 100) as i64;
+
+    // Ask for one extra so we know whether there's another page
+    let category = query.category.as_deref().map(normalize::category);
+    let mut gifs = store
+        .list(
+            query.after,
+            category.as_deref(),
+            query.url.as_deref(),
+            limit + 1,
+        )
+        .await?;
+
+    let next = if gifs.len() as i64 > limit {
+        gifs.truncate(limit as usize);
+        gifs.last().map(|gif| gif.id)
+    } else {
+        None
+    };
+
+    Ok(warp::reply::json(&Gifs { gifs, next }))
+}
+
+async fn get_categories(store: Store) -> Result<impl Reply, Rejection> {
+    let categories = store.categories().await?;
+
+    Ok(warp::reply::json(&categories))
+}
+
+async fn patch_gif(
+    id: i64,
+    mut patch: GifPatch,
+    store: Store,
+    rules: Arc<UrlRules>,
+) -> Result<impl Reply, Rejection> {
+    if let Some(url) = &patch.url {
+        let url = rules.check(url).map_err(reject::custom)?;
+        patch.url = Some(normalize::url(url).to_string());
+    }
+    if patch.weight.is_some_and(|weight| !valid_weight(weight)) {
+        return Err(reject::custom(BadRequest));
+    }
+    if let Some(tags) = &patch.tags {
+        patch.tags = Some(clean_tags(tags.iter().map(String::as_str)));
+    }
+    if let Some(category) = &patch.category {
+        let category = normalize::category(category);
+        if category.is_empty() {
+            return Err(reject::custom(BadRequest));
+        }
+        patch.category = Some(category);
+    }
+
+    match store.update(id, &patch).await? {
+        Some(gif) => Ok(warp::reply::json(&gif)),
+        None => Err(reject::custom(NotFound)),
+    }
+}
+
+/// Trims and lowercases tags, dropping empty ones and repeats. Comes back
+/// sorted.
+fn clean_tags<'a>(tags: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+fn valid_weight(weight: f64) -> bool {
+    (0.0..=MAX_WEIGHT).contains(&weight)
+}
+
+async fn delete_gif(id: i64, store: Store) -> Result<impl Reply, Rejection> {
+    if store.delete(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(reject::custom(NotFound))
+    }
+}
+
+async fn get_aliases(store: Store) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&store.aliases().await?))
+}
+
+async fn put_alias(
+    alias: String,
+    target: AliasTarget,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let alias = Alias {
+        alias: normalize::category(&alias),
+        category: normalize::category(&target.category),
+    };
+    if alias.alias.is_empty() || alias.category.is_empty() || alias.alias == alias.category {
+        return Err(reject::custom(BadRequest));
+    }
+
+    Ok(warp::reply::json(&store.set_alias(alias).await?))
+}
+
+async fn delete_alias(alias: String, store: Store) -> Result<impl Reply, Rejection> {
+    if store.delete_alias(&normalize::category(&alias)).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(reject::custom(NotFound))
+    }
+}
+
+async fn get_subcategories(store: Store) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&store.subcategories().await?))
+}
+
+async fn put_subcategory(
+    parent: String,
+    child: String,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let link = Subcategory {
+        parent: normalize::category(&parent),
+        child: normalize::category(&child),
+    };
+    if link.parent.is_empty() || link.child.is_empty() || link.parent == link.child {
+        return Err(reject::custom(BadRequest));
+    }
+
+    Ok(warp::reply::json(&store.add_subcategory(link).await?))
+}
+
+async fn delete_subcategory(
+    parent: String,
+    child: String,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    if store
+        .remove_subcategory(&normalize::category(&parent), &normalize::category(&child))
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(reject::custom(NotFound))
+    }
+}
+
+async fn post_gifs(
+    new: NewGif,
+    store: Store,
+    rules: Arc<UrlRules>,
+) -> Result<impl Reply, Rejection> {
+    let url = normalize::url(rules.check(&new.url).map_err(reject::custom)?).to_string();
+    let category = normalize::category(&new.category);
+    let weight = new.weight.unwrap_or(1.0);
+    if category.is_empty() || !valid_weight(weight) {
+        return Err(reject::custom(BadRequest));
+    }
+
+    // The same url in the same category is the same gif, so hand back the one we have
+    if let Some(gif) = store.find_by_url(&category, &url).await? {
+        return Ok(with_location(gif, StatusCode::OK));
+    }
+
+    let tags = clean_tags(new.tags.iter().map(String::as_str));
+
+    let mut attempts = 0;
+    loop {
+        let gif = Gif {
+            id: gen_flake().await,
+            url: url.clone(),
+            category: category.clone(),
+            source: new.source.clone(),
+            weight,
+            tags: tags.clone(),
+        };
+        let id = gif.id;
+
+        match store.insert(gif).await {
+            Ok(gif) => return Ok(with_location(gif, StatusCode::CREATED)),
+            // Someone else added it between the lookup and the insert
+            Err(error::Error::Conflict(err)) => {
+                if let Some(gif) = store.find_by_url(&category, &url).await? {
+                    return Ok(with_location(gif, StatusCode::OK));
+                }
+                // Another instance handed out the same id, so try a fresh one
+                attempts += 1;
+                if attempts < MAX_ID_ATTEMPTS && store.get(id).await?.is_some() {
+                    continue;
+                }
+                return Err(error::Error::Conflict(err).into());
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Replies with `gif` and a `Location` header pointing at it.
+fn with_location(gif: Gif, status: StatusCode) -> impl Reply {
+    let location = format!("/api/gifs/{}", gif.id);
+    let reply = warp::reply::with_status(warp::reply::json(&gif), status);
+    warp::reply::with_header(reply, "location", location)
+}
+
+lazy_static! {
+    // Shared so ids made in the same millisecond still differ by sequence.
     // Using Discord epoch
-    let mut snowflake = Snowflake::new(1420070400000, 1, 1);
-    return snowflake.generate();
+    static ref FLAKE: Mutex<Snowflake> = Mutex::new(Snowflake::new(1420070400000, 1, 1));
+}
+
+async fn gen_flake() -> i64 {
+    FLAKE.lock().unwrap().generate()
 }
 
 /// An API error serializable to JSON.
@@ -149,26 +965,772 @@ struct ErrorMessage {
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
+    let mut retry_after = None;
 
-    if err.is_not_found() {
+    if let Some(limited) = err.find::<ratelimit::RateLimited>() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = "RATE_LIMITED";
+        retry_after = Some(limited.retry_after);
+    } else if err.is_not_found() || err.find::<NotFound>().is_some() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    } else if err.find::<BadRequest>().is_some()
+        || err.find::<warp::reject::InvalidQuery>().is_some()
+    {
+        code = StatusCode::BAD_REQUEST;
+        message = "BAD_REQUEST";
+    } else if let Some(invalid) = err.find::<validate::InvalidUrl>() {
+        code = StatusCode::UNPROCESSABLE_ENTITY;
+        message = invalid.code();
+    } else if err.find::<warp::body::BodyDeserializeError>().is_some() {
+        // The JSON body was malformed or missing a required field
+        code = StatusCode::BAD_REQUEST;
+        message = "BAD_REQUEST";
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        message = "PAYLOAD_TOO_LARGE";
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        message = "UNSUPPORTED_MEDIA_TYPE";
+    } else if err.find::<auth::Unauthorized>().is_some() {
+        code = StatusCode::UNAUTHORIZED;
+        message = "UNAUTHORIZED";
+    } else if err.find::<auth::Forbidden>().is_some() {
+        code = StatusCode::FORBIDDEN;
+        message = "FORBIDDEN";
+    } else if let Some(err) = err.find::<error::Error>() {
+        // A conflict is the client's doing, anything else is worth a look
+        if err.status().is_server_error() {
+            tracing::error!(error = %err, code = err.code(), "database error");
+        }
+        code = err.status();
+        message = err.code();
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         // We can handle a specific error, here METHOD_NOT_ALLOWED,
         // and render it however we want
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED";
     } else {
         // We should have expected this... Just log and say its a 500
-        eprintln!("unhandled rejection: {:?}", err);
+        tracing::error!(rejection = ?err, "unhandled rejection");
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "UNHANDLED_REJECTION";
     }
 
+    metrics::error(message);
+
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
         message: message.into(),
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if let Some(seconds) = retry_after {
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from(seconds));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+    use warp::test::request;
+
+    const ADMIN_KEY: &str = "gif_test_admin";
+    const WRITE_KEY: &str = "gif_test_write";
+    const READ_KEY: &str = "gif_test_read";
+
+    /// A memory store holding one admin key, one write key and one
+    /// read-only key.
+    async fn store() -> Store {
+        let store: Store = Arc::new(MemoryStore::new());
+        let keys = [
+            (1, WRITE_KEY, Scope::Write),
+            (2, READ_KEY, Scope::Read),
+            (3, ADMIN_KEY, Scope::Admin),
+        ];
+        for (id, key, scope) in &keys {
+            store
+                .insert_api_key(ApiKey {
+                    id: *id,
+                    name: key.to_string(),
+                    key_hash: auth::hash_key(key),
+                    scopes: vec![*scope],
+                    created_at: 0,
+                })
+                .await
+                .unwrap();
+        }
+        store
+    }
+
+    fn api(
+        store: Store,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        routes(
+            store,
+            Arc::new(UrlRules {
+                allowed_hosts: Vec::new(),
+            }),
+            Arc::new(limiter),
+            Arc::new(Bags::new(Duration::from_secs(60))),
+            None,
+        )
+    }
+
+    fn unlimited() -> RateLimiter {
+        RateLimiter::new(0, 0, false)
+    }
+
+    fn body(response: &warp::http::Response<impl AsRef<[u8]>>) -> Value {
+        serde_json::from_slice(response.body().as_ref()).unwrap()
+    }
+
+    fn bearer(key: &str) -> String {
+        format!("Bearer {}", key)
+    }
+
+    /// Adds a gif through the api and returns it.
+    async fn add_gif(
+        api: &(impl Filter<Extract = impl Reply, Error = Infallible> + Clone + 'static),
+        gif: Value,
+    ) -> Value {
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&gif)
+            .reply(api)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        body(&response)
+    }
+
+    /// Sends an admin `method` request to `path` with `json` as the body.
+    async fn admin(
+        api: &(impl Filter<Extract = impl Reply, Error = Infallible> + Clone + 'static),
+        method: &str,
+        path: &str,
+        json: Value,
+    ) -> warp::http::Response<warp::hyper::body::Bytes> {
+        request()
+            .method(method)
+            .path(path)
+            .header("authorization", bearer(ADMIN_KEY))
+            .json(&json)
+            .reply(api)
+            .await
+    }
+
+    /// Asserts `response` is an `ErrorMessage` with `status` and `message`.
+    fn assert_error(
+        response: &warp::http::Response<impl AsRef<[u8]>>,
+        status: StatusCode,
+        message: &str,
+    ) {
+        assert_eq!(response.status(), status);
+        assert_eq!(
+            body(response),
+            json!({ "code": status.as_u16(), "message": message })
+        );
+    }
+
+    #[tokio::test]
+    async fn random_gif_from_an_empty_category_is_not_found() {
+        let api = api(store().await, unlimited());
+
+        let response = request().path("/api/gif/hug").reply(&api).await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn posted_gif_is_created_and_drawn() {
+        let api = api(store().await, unlimited());
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "url": "https://media.tenor.com/a.gif", "category": "Hug" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let gif = body(&response);
+        assert_eq!(gif["category"], "hug");
+        let location = format!("/api/gifs/{}", gif["id"]);
+        assert_eq!(response.headers()["location"], location.as_str());
+
+        let response = request().path("/api/gif/hug").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&response), gif);
+
+        // The same url again hands back the gif that's already there
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "url": "https://media.tenor.com/a.gif", "category": "hug" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["location"], location.as_str());
+    }
+
+    #[tokio::test]
+    async fn gif_is_patched_then_deleted() {
+        let api = api(store().await, unlimited());
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "url": "https://media.tenor.com/a.gif", "category": "hug" }))
+            .reply(&api)
+            .await;
+        let path = format!("/api/gifs/{}", body(&response)["id"]);
+
+        let response = request()
+            .method("PATCH")
+            .path(&path)
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "category": "pat", "tags": ["Cute", "cute"] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&response)["category"], "pat");
+        assert_eq!(body(&response)["tags"], json!(["cute"]));
+
+        let response = request().path(&path).reply(&api).await;
+        assert_eq!(body(&response)["category"], "pat");
+
+        let response = request()
+            .method("DELETE")
+            .path(&path)
+            .header("authorization", bearer(WRITE_KEY))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = request().path(&path).reply(&api).await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+
+        let response = request()
+            .method("DELETE")
+            .path(&path)
+            .header("authorization", bearer(WRITE_KEY))
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn gifs_deleted_elsewhere_are_picked_around() {
+        let inner: Store = Arc::new(MemoryStore::new());
+        let store: Store = Arc::new(CachedStore::new(inner.clone(), Duration::from_secs(60)));
+        for id in 1..=2 {
+            store
+                .insert(Gif {
+                    id,
+                    url: format!("https://media.tenor.com/{}.gif", id),
+                    category: "hug".into(),
+                    source: None,
+                    weight: 1.0,
+                    tags: Vec::new(),
+                })
+                .await
+                .unwrap();
+        }
+        let api = api(store, unlimited());
+
+        let response = request().path("/api/gif/hug?count=2").reply(&api).await;
+        assert_eq!(body(&response).as_array().unwrap().len(), 2);
+
+        // Another instance deletes one, which this one's cache doesn't see
+        inner.delete(1).await.unwrap();
+
+        for _ in 0..10 {
+            let response = request().path("/api/gif/hug").reply(&api).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(&response)["id"], 2);
+        }
+        let response = request().path("/api/gif/hug?count=2").reply(&api).await;
+        assert_eq!(body(&response).as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn aliases_draw_from_their_category() {
+        let api = api(store().await, unlimited());
+        let gif = add_gif(
+            &api,
+            json!({ "url": "https://media.tenor.com/a.gif", "category": "hug" }),
+        )
+        .await;
+
+        let response = admin(
+            &api,
+            "PUT",
+            "/api/aliases/Cuddle",
+            json!({ "category": "Hug" }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(&response),
+            json!({ "alias": "cuddle", "category": "hug" })
+        );
+
+        let response = request().path("/api/aliases").reply(&api).await;
+        assert_eq!(
+            body(&response),
+            json!([{ "alias": "cuddle", "category": "hug" }])
+        );
+
+        let response = request().path("/api/gif/cuddle").reply(&api).await;
+        assert_eq!(body(&response), gif);
+
+        let response = admin(&api, "DELETE", "/api/aliases/cuddle", json!({})).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = request().path("/api/gif/cuddle").reply(&api).await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+
+        let response = admin(
+            &api,
+            "PUT",
+            "/api/aliases/hug",
+            json!({ "category": "HUG" }),
+        )
+        .await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn parents_draw_from_their_children() {
+        let api = api(store().await, unlimited());
+        let hug = add_gif(
+            &api,
+            json!({ "url": "https://media.tenor.com/a.gif", "category": "hug" }),
+        )
+        .await;
+        let pat = add_gif(
+            &api,
+            json!({ "url": "https://media.tenor.com/b.gif", "category": "pat" }),
+        )
+        .await;
+
+        for path in &[
+            "/api/categories/affection/children/hug",
+            "/api/categories/hug/children/pat",
+        ] {
+            let response = admin(&api, "PUT", path, json!({})).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = request()
+            .path("/api/gif/affection?count=50")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            ids(&body(&response)),
+            vec![hug["id"].as_i64().unwrap(), pat["id"].as_i64().unwrap()]
+        );
+
+        // A child never draws from its parent
+        let response = request().path("/api/gif/pat?count=50").reply(&api).await;
+        assert_eq!(body(&response), json!([pat]));
+
+        // Loops don't draw anything twice or hang
+        let response = admin(
+            &api,
+            "PUT",
+            "/api/categories/pat/children/affection",
+            json!({}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request().path("/api/gif/pat?count=50").reply(&api).await;
+        assert_eq!(body(&response).as_array().unwrap().len(), 2);
+
+        let response = admin(&api, "PUT", "/api/categories/hug/children/Hug", json!({})).await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let response = admin(
+            &api,
+            "DELETE",
+            "/api/categories/hug/children/pat",
+            json!({}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = request().path("/api/gif/hug?count=50").reply(&api).await;
+        assert_eq!(body(&response), json!([hug]));
+    }
+
+    #[tokio::test]
+    async fn aliases_and_subcategories_need_the_admin_scope() {
+        let api = api(store().await, unlimited());
+        let routes = [
+            ("PUT", "/api/aliases/cuddle"),
+            ("DELETE", "/api/aliases/cuddle"),
+            ("PUT", "/api/categories/affection/children/hug"),
+            ("DELETE", "/api/categories/affection/children/hug"),
+        ];
+
+        for (method, path) in &routes {
+            let response = request()
+                .method(method)
+                .path(path)
+                .json(&json!({ "category": "hug" }))
+                .reply(&api)
+                .await;
+            assert_error(&response, StatusCode::UNAUTHORIZED, "UNAUTHORIZED");
+
+            for key in &[READ_KEY, WRITE_KEY] {
+                let response = request()
+                    .method(method)
+                    .path(path)
+                    .header("authorization", bearer(key))
+                    .json(&json!({ "category": "hug" }))
+                    .reply(&api)
+                    .await;
+                assert_error(&response, StatusCode::FORBIDDEN, "FORBIDDEN");
+            }
+        }
+
+        let response = request().path("/api/aliases").reply(&api).await;
+        assert_eq!(body(&response), json!([]));
+    }
+
+    #[tokio::test]
+    async fn aliases_share_a_shuffle_bag() {
+        let api = api(store().await, unlimited());
+        let mut added = Vec::new();
+        for n in 0..3 {
+            let url = format!("https://media.tenor.com/{}.gif", n);
+            let gif = add_gif(&api, json!({ "url": url, "category": "hug" })).await;
+            added.push(gif["id"].as_i64().unwrap());
+        }
+        let response = admin(
+            &api,
+            "PUT",
+            "/api/aliases/cuddle",
+            json!({ "category": "hug" }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Every gif comes up once before any repeats, whichever name is asked for
+        let mut drawn = Vec::new();
+        for path in &[
+            "/api/gif/hug?session=abc",
+            "/api/gif/cuddle?session=abc",
+            "/api/gif/hug?session=abc",
+        ] {
+            let response = request().path(path).reply(&api).await;
+            drawn.push(body(&response)["id"].as_i64().unwrap());
+        }
+        drawn.sort_unstable();
+        added.sort_unstable();
+        assert_eq!(drawn, added);
+    }
+
+    /// The sorted ids of the gifs in a json array.
+    fn ids(gifs: &Value) -> Vec<i64> {
+        let mut ids: Vec<i64> = gifs
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|gif| gif["id"].as_i64().unwrap())
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[tokio::test]
+    async fn tags_filter_by_all_or_any() {
+        let api = api(store().await, unlimited());
+        let mut added = Vec::new();
+        for (n, tags) in [json!(["cute", "soft"]), json!(["Cute"]), json!([])]
+            .iter()
+            .enumerate()
+        {
+            let url = format!("https://media.tenor.com/{}.gif", n);
+            let gif = add_gif(&api, json!({ "url": url, "category": "hug", "tags": tags })).await;
+            added.push(gif["id"].as_i64().unwrap());
+        }
+
+        let response = request()
+            .path("/api/gif/hug?count=50&tags=cute,soft")
+            .reply(&api)
+            .await;
+        assert_eq!(ids(&body(&response)), vec![added[0]]);
+
+        let response = request()
+            .path("/api/gif/hug?count=50&tags=cute,soft&match=any")
+            .reply(&api)
+            .await;
+        assert_eq!(ids(&body(&response)), vec![added[0], added[1]]);
+
+        let response = request()
+            .path("/api/gif/hug?count=50&tags=%20CUTE%20")
+            .reply(&api)
+            .await;
+        assert_eq!(ids(&body(&response)), vec![added[0], added[1]]);
+
+        let response = request().path("/api/gif/hug?tags=sleepy").reply(&api).await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+
+        let response = request()
+            .path("/api/gif/hug?tags=cute&match=most")
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let response = request().path("/api/gif/hug?tags=,").reply(&api).await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let tags = |count: usize| {
+            let tags: Vec<String> = (0..count).map(|n| format!("t{}", n)).collect();
+            format!("/api/gif/hug?match=any&tags=cute,{}", tags.join(","))
+        };
+        let response = request().path(&tags(MAX_TAGS - 1)).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request().path(&tags(MAX_TAGS)).reply(&api).await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn patch_replaces_every_tag() {
+        let api = api(store().await, unlimited());
+        let gif = add_gif(
+            &api,
+            json!({ "url": "https://media.tenor.com/a.gif", "category": "hug", "tags": ["cute", "soft"] }),
+        )
+        .await;
+        let path = format!("/api/gifs/{}", gif["id"]);
+        let patch = |patch: Value| {
+            request()
+                .method("PATCH")
+                .path(&path)
+                .header("authorization", bearer(WRITE_KEY))
+                .json(&patch)
+        };
+
+        let response = patch(json!({ "tags": ["Sleepy", " sleepy "] }))
+            .reply(&api)
+            .await;
+        assert_eq!(body(&response)["tags"], json!(["sleepy"]));
+
+        let response = request().path("/api/gif/hug?tags=cute").reply(&api).await;
+        assert_error(&response, StatusCode::NOT_FOUND, "NOT_FOUND");
+        let response = request().path("/api/gif/hug?tags=sleepy").reply(&api).await;
+        assert_eq!(body(&response)["id"], gif["id"]);
+
+        // Leaving `tags` out keeps them, an empty list clears them
+        let response = patch(json!({ "weight": 2.0 })).reply(&api).await;
+        assert_eq!(body(&response)["tags"], json!(["sleepy"]));
+        let response = patch(json!({ "tags": [] })).reply(&api).await;
+        assert_eq!(body(&response)["tags"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn gifs_are_listed_a_page_at_a_time() {
+        let store = store().await;
+        for id in 1..=105 {
+            store
+                .insert(Gif {
+                    id,
+                    url: format!("https://media.tenor.com/Cat{}.gif", id),
+                    category: if id % 2 == 1 { "hug" } else { "pat" }.into(),
+                    source: None,
+                    weight: 1.0,
+                    tags: Vec::new(),
+                })
+                .await
+                .unwrap();
+        }
+        let api = api(store, unlimited());
+        let page = |path: &'static str| {
+            let api = api.clone();
+            async move {
+                let response = request().path(path).reply(&api).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let page = body(&response);
+                let ids: Vec<i64> = page["gifs"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|gif| gif["id"].as_i64().unwrap())
+                    .collect();
+                (ids, page["next"].as_i64())
+            }
+        };
+
+        assert_eq!(page("/api/gifs").await, ((1..=50).collect(), Some(50)));
+        assert_eq!(
+            page("/api/gifs?after=50").await,
+            ((51..=100).collect(), Some(100))
+        );
+        assert_eq!(
+            page("/api/gifs?after=100").await,
+            ((101..=105).collect(), None)
+        );
+        // A last page that's exactly full has no `next` either
+        assert_eq!(
+            page("/api/gifs?after=55").await,
+            ((56..=105).collect(), None)
+        );
+        assert_eq!(page("/api/gifs?after=105").await, (Vec::new(), None));
+
+        // The limit is kept between 1 and 100
+        assert_eq!(page("/api/gifs?limit=0").await, (vec![1], Some(1)));
+        assert_eq!(
+            page("/api/gifs?limit=1000").await,
+            ((1..=100).collect(), Some(100))
+        );
+
+        assert_eq!(
+            page("/api/gifs?category=%20HUG%20&limit=100").await,
+            ((1..=105).step_by(2).collect(), None)
+        );
+        assert_eq!(
+            page("/api/gifs?category=pat&after=90").await,
+            ((92..=104).step_by(2).collect(), None)
+        );
+
+        // The url filter is a case-insensitive substring match
+        let (ids, next) = page("/api/gifs?url=cAT10&limit=100").await;
+        assert_eq!(ids, vec![10, 100, 101, 102, 103, 104, 105]);
+        assert_eq!(next, None);
+        let (ids, _) = page("/api/gifs?url=cat1.&category=hug").await;
+        assert_eq!(ids, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn writes_need_a_key_with_the_write_scope() {
+        let api = api(store().await, unlimited());
+        let gif = json!({ "url": "https://media.tenor.com/a.gif", "category": "hug" });
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .json(&gif)
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::UNAUTHORIZED, "UNAUTHORIZED");
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer("gif_made_up"))
+            .json(&gif)
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::UNAUTHORIZED, "UNAUTHORIZED");
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(READ_KEY))
+            .json(&gif)
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::FORBIDDEN, "FORBIDDEN");
+
+        let response = request()
+            .method("DELETE")
+            .path("/api/gifs/1")
+            .header("authorization", bearer(READ_KEY))
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::FORBIDDEN, "FORBIDDEN");
+    }
+
+    #[tokio::test]
+    async fn rejections_map_to_status_and_code() {
+        let api = api(store().await, unlimited());
+
+        let response = request().path("/api/gif/hug?count=0").reply(&api).await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let response = request().path("/api/gif/hug?count=two").reply(&api).await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .body("not json")
+            .reply(&api)
+            .await;
+        assert_error(&response, StatusCode::BAD_REQUEST, "BAD_REQUEST");
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "url": "ftp://media.tenor.com/a.gif", "category": "hug" }))
+            .reply(&api)
+            .await;
+        assert_error(
+            &response,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "URL_SCHEME_NOT_ALLOWED",
+        );
+
+        let response = request()
+            .method("POST")
+            .path("/api/gifs")
+            .header("authorization", bearer(WRITE_KEY))
+            .json(&json!({ "url": "https://example.com/a.txt", "category": "hug" }))
+            .reply(&api)
+            .await;
+        assert_error(
+            &response,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "URL_NOT_AN_IMAGE",
+        );
+
+        let response = request().method("PUT").path("/api/gifs").reply(&api).await;
+        assert_error(
+            &response,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "METHOD_NOT_ALLOWED",
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_timed_by_route_and_status() {
+        let api = api(store().await, unlimited());
+
+        request().path("/api/gif/nothing-here").reply(&api).await;
+        let response = request().path("/metrics").reply(&api).await;
+        let text = String::from_utf8_lossy(response.body());
+        assert!(text.contains(
+            r#"gif_http_request_duration_seconds_count{route="random_gif",status="404"}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn clients_over_their_limit_are_told_when_to_retry() {
+        let api = api(store().await, RateLimiter::new(1, 0, false));
+
+        // Paths that aren't routes don't cost anything
+        for _ in 0..2 {
+            let response = request().path("/favicon.ico").reply(&api).await;
+            assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let response = request().path("/api/categories").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = request().path("/api/categories").reply(&api).await;
+        assert_error(&response, StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED");
+        assert_eq!(response.headers()["retry-after"], "60");
+    }
 }
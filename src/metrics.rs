@@ -0,0 +1,96 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use warp::http::Method;
+use warp::log::Info;
+use warp::Reply;
+
+use crate::db::Db;
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "gif_http_requests_total",
+        "HTTP requests by route and status",
+        &["route", "status"]
+    )
+    .unwrap();
+    static ref DURATION: HistogramVec = register_histogram_vec!(
+        "gif_http_request_duration_seconds",
+        "Time to answer HTTP requests by route and status",
+        &["route", "status"]
+    )
+    .unwrap();
+    static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "gif_http_errors_total",
+        "Error replies by `ErrorMessage` code",
+        &["code"]
+    )
+    .unwrap();
+    static ref POOL_SIZE: IntGauge =
+        register_int_gauge!("gif_db_pool_connections", "Open database connections").unwrap();
+    static ref POOL_IDLE: IntGauge = register_int_gauge!(
+        "gif_db_pool_idle_connections",
+        "Open database connections that aren't in use"
+    )
+    .unwrap();
+}
+
+/// Names the route a request was for, so labels don't grow with every
+/// category and id that gets asked for.
+fn route(method: &Method, path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method.as_str(), segments.as_slice()) {
+        ("GET", ["healthz"]) => "healthz",
+        ("GET", ["readyz"]) => "readyz",
+        ("GET", ["metrics"]) => "metrics",
+        ("GET", ["api", "gif", _]) => "random_gif",
+        ("GET", ["api", "gifs"]) => "list_gifs",
+        ("POST", ["api", "gifs"]) => "add_gif",
+        ("GET", ["api", "gifs", _]) => "get_gif",
+        ("PATCH", ["api", "gifs", _]) => "update_gif",
+        ("DELETE", ["api", "gifs", _]) => "delete_gif",
+        ("GET", ["api", "categories"]) => "categories",
+        ("GET", ["api", "aliases"]) => "aliases",
+        ("PUT", ["api", "aliases", _]) => "set_alias",
+        ("DELETE", ["api", "aliases", _]) => "delete_alias",
+        ("GET", ["api", "subcategories"]) => "subcategories",
+        ("PUT", ["api", "categories", _, "children", _]) => "add_subcategory",
+        ("DELETE", ["api", "categories", _, "children", _]) => "remove_subcategory",
+        ("GET", ["re", _]) => "re",
+        ("GET", [seconds]) if seconds.parse::<u8>().is_ok() => "wait",
+        _ => "unmatched",
+    }
+}
+
+/// Counts and times a finished request. Meant for `warp::log::custom`.
+pub fn record(info: Info) {
+    let route = route(info.method(), info.path());
+    let status = info.status();
+    let labels = [route, status.as_str()];
+    REQUESTS.with_label_values(&labels).inc();
+    DURATION
+        .with_label_values(&labels)
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// Counts an error reply from `handle_rejection`.
+pub fn error(code: &str) {
+    ERRORS.with_label_values(&[code]).inc();
+}
+
+/// Everything collected so far, in the Prometheus text format.
+pub fn render(db: Option<&Db>) -> impl Reply {
+    if let Some(db) = db {
+        let stats = db.stats();
+        POOL_SIZE.set(stats.size.into());
+        POOL_IDLE.set(stats.idle.into());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    // Only fails on a broken metric family, which registration already rules out
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    warp::reply::with_header(buffer, "content-type", encoder.format_type())
+}
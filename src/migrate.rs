@@ -0,0 +1,605 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::Utc;
+use sqlx::{Executor, Postgres, Row, Transaction};
+use url::Url;
+
+use crate::db::Db;
+use crate::normalize;
+
+/// A single versioned schema change, embedded into the binary at build time.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+    /// Used instead of `down` on SQLite, for changes it can't undo in place.
+    pub sqlite_down: Option<&'static str>,
+    /// Runs after `up`, in the same transaction.
+    pub backfill: Option<Backfill>,
+}
+
+impl Migration {
+    /// The script that reverts this migration on `db`.
+    fn down_for(&self, db: &Db) -> &'static str {
+        match db {
+            Db::Postgres(_) => self.down,
+            Db::Sqlite(_) => self.sqlite_down.unwrap_or(self.down),
+        }
+    }
+}
+
+/// Data changes that need Rust rather than SQL.
+#[derive(Clone, Copy, Debug)]
+pub enum Backfill {
+    /// Drops the newer copies of gifs added twice and adds the unique
+    /// `(category, url)` index, logging what it dropped.
+    DedupeGifs,
+    /// Rewrites every category, alias and subcategory with
+    /// `normalize::category`, which SQL can't match.
+    NormalizeCategories,
+    /// Rewrites every gif's url with `normalize::url`.
+    NormalizeUrls,
+}
+
+// `sqlite_down` also embeds `<name>.sqlite.down.sql`, and `backfill = X`
+// runs `Backfill::X` after the up script.
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $name, ".down.sql")),
+            sqlite_down: None,
+            backfill: None,
+        }
+    };
+    ($version:expr, $name:expr, sqlite_down) => {
+        Migration {
+            sqlite_down: Some(include_str!(concat!(
+                "../migrations/",
+                $name,
+                ".sqlite.down.sql"
+            ))),
+            ..migration!($version, $name)
+        }
+    };
+    ($version:expr, $name:expr, backfill = $backfill:ident) => {
+        Migration {
+            backfill: Some(Backfill::$backfill),
+            ..migration!($version, $name)
+        }
+    };
+}
+
+// Runs a migration script, its backfill if any and its bookkeeping query in
+// `tx`.
+macro_rules! apply {
+    ($tx:ident, $script:expr, $backfill:expr, $placeholders:tt, $record:expr) => {{
+        (&mut $tx).execute($script).await?;
+        match $backfill {
+            Some(Backfill::DedupeGifs) => dedupe_gifs!($tx),
+            Some(Backfill::NormalizeCategories) => normalize_categories!($tx, $placeholders),
+            Some(Backfill::NormalizeUrls) => normalize_urls!($tx, $placeholders),
+            None => {}
+        }
+        $record.execute(&mut $tx).await?;
+    }};
+}
+
+// `apply!` in a transaction of its own.
+macro_rules! transaction {
+    ($pool:expr, $script:expr, $backfill:expr, $placeholders:tt, $record:expr) => {{
+        let mut tx = $pool.begin().await?;
+        apply!(tx, $script, $backfill, $placeholders, $record);
+        tx.commit().await?;
+    }};
+}
+
+/// Drops the newer copies of gifs that are in the same category with the
+/// same url, then puts back the index that keeps it that way.
+const DEDUPE_GIFS: &str = "
+    DELETE FROM gif_gifs
+    WHERE id NOT IN (SELECT MIN(id) FROM gif_gifs GROUP BY category, url);
+
+    DELETE FROM gif_tags
+    WHERE gif_id NOT IN (SELECT id FROM gif_gifs);
+
+    CREATE UNIQUE INDEX IF NOT EXISTS gif_gifs_category_url_idx ON gif_gifs (category, url);
+    ";
+
+// Runs `DEDUPE_GIFS`, first logging the ids it's about to drop since no down
+// script can bring them back.
+macro_rules! dedupe_gifs {
+    ($tx:ident) => {{
+        let ids: Vec<i64> = sqlx::query(
+            "select id from gif_gifs
+            where id not in (select min(id) from gif_gifs group by category, url)
+            order by id",
+        )
+        .fetch_all(&mut $tx)
+        .await?
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+        if !ids.is_empty() {
+            tracing::warn!(count = ids.len(), ids = ?ids, "dropping duplicate gifs");
+        }
+        (&mut $tx).execute(DEDUPE_GIFS).await?;
+    }};
+}
+
+// `Backfill::NormalizeCategories` for either backend, given its two
+// placeholders. Where normalizing makes two things the same the oldest gif
+// and the alphabetically first alias win.
+macro_rules! normalize_categories {
+    ($tx:ident, ($p1:literal, $p2:literal)) => {{
+        let rows = sqlx::query("select distinct category from gif_gifs")
+            .fetch_all(&mut $tx)
+            .await?;
+        for row in rows {
+            let category: String = row.get("category");
+            let normalized = normalize::category(&category);
+            if normalized != category {
+                sqlx::query(concat!(
+                    "update gif_gifs set category = ",
+                    $p1,
+                    " where category = ",
+                    $p2
+                ))
+                .bind(normalized)
+                .bind(category)
+                .execute(&mut $tx)
+                .await?;
+            }
+        }
+        dedupe_gifs!($tx);
+
+        let rows = sqlx::query("select alias, category from gif_category_aliases order by alias")
+            .fetch_all(&mut $tx)
+            .await?;
+        let mut aliases = BTreeMap::new();
+        for row in rows {
+            let alias = normalize::category(row.get("alias"));
+            let category = normalize::category(row.get("category"));
+            aliases.entry(alias).or_insert(category);
+        }
+        (&mut $tx)
+            .execute("delete from gif_category_aliases")
+            .await?;
+        for (alias, category) in aliases {
+            if alias.is_empty() || category.is_empty() || alias == category {
+                continue;
+            }
+            sqlx::query(concat!(
+                "insert into gif_category_aliases (alias, category) values (",
+                $p1,
+                ", ",
+                $p2,
+                ")"
+            ))
+            .bind(alias)
+            .bind(category)
+            .execute(&mut $tx)
+            .await?;
+        }
+
+        let rows = sqlx::query("select parent, child from gif_subcategories")
+            .fetch_all(&mut $tx)
+            .await?;
+        let links: BTreeSet<(String, String)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    normalize::category(row.get("parent")),
+                    normalize::category(row.get("child")),
+                )
+            })
+            .filter(|(parent, child)| !parent.is_empty() && !child.is_empty() && parent != child)
+            .collect();
+        (&mut $tx).execute("delete from gif_subcategories").await?;
+        for (parent, child) in links {
+            sqlx::query(concat!(
+                "insert into gif_subcategories (parent, child) values (",
+                $p1,
+                ", ",
+                $p2,
+                ")"
+            ))
+            .bind(parent)
+            .bind(child)
+            .execute(&mut $tx)
+            .await?;
+        }
+    }};
+}
+
+// `Backfill::NormalizeUrls` for either backend, given its two placeholders.
+// Urls that don't parse are left alone. Where normalizing makes two the
+// same the oldest gif wins.
+macro_rules! normalize_urls {
+    ($tx:ident, ($p1:literal, $p2:literal)) => {{
+        let rows = sqlx::query("select id, url from gif_gifs")
+            .fetch_all(&mut $tx)
+            .await?;
+        for row in rows {
+            let url: String = row.get("url");
+            let normalized = match Url::parse(url.trim()) {
+                Ok(parsed) => normalize::url(parsed).to_string(),
+                Err(_) => continue,
+            };
+            if normalized != url {
+                sqlx::query(concat!(
+                    "update gif_gifs set url = ",
+                    $p1,
+                    " where id = ",
+                    $p2
+                ))
+                .bind(normalized)
+                .bind(row.get::<i64, _>("id"))
+                .execute(&mut $tx)
+                .await?;
+            }
+        }
+        dedupe_gifs!($tx);
+    }};
+}
+
+/// Every migration the binary knows about, oldest first.
+pub static MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create_gif_gifs"),
+    migration!(2, "0002_add_gif_source_and_tags", sqlite_down),
+    migration!(3, "0003_create_api_keys"),
+    migration!(4, "0004_unique_gif_url_per_category", backfill = DedupeGifs),
+    migration!(5, "0005_add_gif_weight", sqlite_down),
+    migration!(6, "0006_create_category_aliases_and_subcategories"),
+    migration!(
+        7,
+        "0007_normalize_categories",
+        backfill = NormalizeCategories
+    ),
+    migration!(8, "0008_normalize_gif_urls", backfill = NormalizeUrls),
+    migration!(
+        9,
+        "0009_case_fold_categories",
+        backfill = NormalizeCategories
+    ),
+];
+
+/// Postgres advisory lock key held while migrating, so replicas starting
+/// together take turns instead of applying the same migration twice.
+const LOCK_KEY: i64 = 0x6769_665f_6d69_6772;
+
+/// Takes the migration lock until `tx` ends.
+async fn lock(tx: &mut Transaction<'_, Postgres>) -> anyhow::Result<()> {
+    sqlx::query("select pg_advisory_xact_lock($1)")
+        .bind(LOCK_KEY)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS gif_migrations (
+        version BIGINT PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_at BIGINT NOT NULL
+    )
+    ";
+
+async fn ensure_table(db: &Db) -> anyhow::Result<()> {
+    match db {
+        // Two `create table if not exists` at once can still clash on Postgres
+        Db::Postgres(pool) => {
+            let mut tx = pool.begin().await?;
+            lock(&mut tx).await?;
+            (&mut tx).execute(CREATE_TABLE).await?;
+            tx.commit().await?;
+        }
+        Db::Sqlite(pool) => {
+            pool.execute(CREATE_TABLE).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `gif_migrations` exists yet, without creating it.
+async fn has_table(db: &Db) -> anyhow::Result<bool> {
+    let count: i64 = match db {
+        Db::Postgres(pool) => sqlx::query(
+            "select count(*) from information_schema.tables
+            where table_schema = current_schema() and table_name = 'gif_migrations'",
+        )
+        .fetch_one(pool)
+        .await?
+        .get(0),
+        // `fetch_one` would leave the read open on the connection, and
+        // SQLite won't drop anything while it is
+        Db::Sqlite(pool) => sqlx::query(
+            "select count(*) from sqlite_master where type = 'table' and name = 'gif_migrations'",
+        )
+        .fetch_all(pool)
+        .await?[0]
+            .get(0),
+    };
+    Ok(count > 0)
+}
+
+/// Versions recorded in `gif_migrations`, oldest first. Only reads, so it
+/// works with a read-only role and before anything has been applied.
+pub async fn applied(db: &Db) -> anyhow::Result<Vec<i64>> {
+    if !has_table(db).await? {
+        return Ok(Vec::new());
+    }
+    let query = "select version from gif_migrations order by version";
+    let versions = match db {
+        Db::Postgres(pool) => sqlx::query(query)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get("version"))
+            .collect(),
+        Db::Sqlite(pool) => sqlx::query(query)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get("version"))
+            .collect(),
+    };
+    Ok(versions)
+}
+
+/// Migrations that have not been applied yet, oldest first.
+pub async fn pending(db: &Db) -> anyhow::Result<Vec<&'static Migration>> {
+    let applied = applied(db).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect())
+}
+
+/// Applies every pending migration, each in its own transaction. On
+/// Postgres each one waits for the migration lock first and is skipped if
+/// another replica got there in the meantime.
+pub async fn up(db: &Db) -> anyhow::Result<Vec<i64>> {
+    ensure_table(db).await?;
+    let mut done = Vec::new();
+    for migration in pending(db).await? {
+        let now = Utc::now().timestamp_millis();
+        match db {
+            Db::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+                lock(&mut tx).await?;
+                // Another replica may have applied it while this one waited
+                let applied: i64 =
+                    sqlx::query("select count(*) from gif_migrations where version = $1")
+                        .bind(migration.version)
+                        .fetch_one(&mut tx)
+                        .await?
+                        .get(0);
+                if applied > 0 {
+                    continue;
+                }
+                apply!(
+                    tx,
+                    migration.up,
+                    migration.backfill,
+                    ("$1", "$2"),
+                    sqlx::query(
+                        "insert into gif_migrations (version, name, applied_at) values ($1, $2, $3)"
+                    )
+                    .bind(migration.version)
+                    .bind(migration.name)
+                    .bind(now)
+                );
+                tx.commit().await?;
+            }
+            Db::Sqlite(pool) => transaction!(
+                pool,
+                migration.up,
+                migration.backfill,
+                ("?", "?"),
+                sqlx::query(
+                    "insert into gif_migrations (version, name, applied_at) values (?, ?, ?)"
+                )
+                .bind(migration.version)
+                .bind(migration.name)
+                .bind(now)
+            ),
+        }
+        tracing::info!(migration = migration.name, "applied migration");
+        done.push(migration.version);
+    }
+    Ok(done)
+}
+
+/// Reverts the `steps` most recently applied migrations, newest first.
+pub async fn down(db: &Db, steps: usize) -> anyhow::Result<Vec<i64>> {
+    let mut done = Vec::new();
+    for version in applied(db).await?.into_iter().rev().take(steps) {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.version == version)
+            .ok_or_else(|| {
+                anyhow::anyhow!("no migration with version {} in this build", version)
+            })?;
+        let script = migration.down_for(db);
+        match db {
+            Db::Postgres(pool) => transaction!(
+                pool,
+                script,
+                None,
+                ("$1", "$2"),
+                sqlx::query("delete from gif_migrations where version = $1").bind(version)
+            ),
+            Db::Sqlite(pool) => transaction!(
+                pool,
+                script,
+                None,
+                ("?", "?"),
+                sqlx::query("delete from gif_migrations where version = ?").bind(version)
+            ),
+        }
+        tracing::info!(migration = migration.name, "reverted migration");
+        done.push(version);
+    }
+    Ok(done)
+}
+
+/// Handles `gif migrate [up | down [steps] | status]`.
+pub async fn command(db: &Db, args: &[String]) -> anyhow::Result<()> {
+    match args.first().map(String::as_str) {
+        None | Some("up") => {
+            if up(db).await?.is_empty() {
+                println!("Database is up to date");
+            }
+        }
+        Some("down") => {
+            let steps = match args.get(1) {
+                Some(steps) => steps.parse()?,
+                None => 1,
+            };
+            down(db, steps).await?;
+        }
+        Some("status") => {
+            let applied = applied(db).await?;
+            for migration in MIGRATIONS {
+                let state = if applied.contains(&migration.version) {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{:>7}  {}", state, migration.name);
+            }
+        }
+        Some(other) => anyhow::bail!("unknown migrate command `{}`", other),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+
+    /// A fresh in-memory SQLite database with every migration applied.
+    async fn database() -> (Db, SqlitePool) {
+        // Each connection to `sqlite::memory:` is its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let db = Db::Sqlite(pool.clone());
+        up(&db).await.unwrap();
+        (db, pool)
+    }
+
+    async fn urls(pool: &SqlitePool) -> Vec<(i64, String)> {
+        sqlx::query("select id, url from gif_gifs order by id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get("id"), row.get("url")))
+            .collect()
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn categories_are_normalized_and_deduplicated() {
+        let (db, pool) = database().await;
+        // Back to before 0007, none of which touches the data on the way down
+        down(&db, 3).await.unwrap();
+
+        for (id, category) in &[(1, "Hug"), (2, " HUG "), (3, "Straße"), (4, "STRASSE ")] {
+            sqlx::query("insert into gif_gifs (id, url, category) values (?, 'https://media.tenor.com/a.gif', ?)")
+                .bind(id)
+                .bind(category)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        for (alias, category) in &[("Cuddle", "HUG"), ("cuddle", "Pat"), ("Hug", "hug")] {
+            sqlx::query("insert into gif_category_aliases (alias, category) values (?, ?)")
+                .bind(alias)
+                .bind(category)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        for (parent, child) in &[("Affection", "Hug"), ("affection", "HUG"), ("Hug", "hug")] {
+            sqlx::query("insert into gif_subcategories (parent, child) values (?, ?)")
+                .bind(parent)
+                .bind(child)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        up(&db).await.unwrap();
+
+        let gifs: Vec<(i64, String)> =
+            sqlx::query_as("select id, category from gif_gifs order by id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            gifs,
+            vec![(1, "hug".to_string()), (3, "strasse".to_string())]
+        );
+        // The alphabetically first spelling wins, and an alias for itself goes
+        let aliases: Vec<(String, String)> =
+            sqlx::query_as("select alias, category from gif_category_aliases")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(aliases, vec![("cuddle".to_string(), "hug".to_string())]);
+        let links: Vec<(String, String)> =
+            sqlx::query_as("select parent, child from gif_subcategories")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(links, vec![("affection".to_string(), "hug".to_string())]);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn urls_are_normalized_and_deduplicated() {
+        let (db, pool) = database().await;
+        // Back to before 0008, whose down script leaves the data alone
+        down(&db, 2).await.unwrap();
+
+        for (id, url) in &[
+            (1, "https://media.tenor.com/a.gif"),
+            (2, "https://media.tenor.com/a.gif?utm_source=x#top"),
+            (3, "https://MEDIA.tenor.com/b.gif"),
+            (4, "not a url"),
+        ] {
+            sqlx::query("insert into gif_gifs (id, url, category) values (?, ?, 'hug')")
+                .bind(id)
+                .bind(url)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("insert into gif_tags (gif_id, tag) values (2, 'cute')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        up(&db).await.unwrap();
+
+        assert_eq!(
+            urls(&pool).await,
+            vec![
+                (1, "https://media.tenor.com/a.gif".to_string()),
+                (3, "https://media.tenor.com/b.gif".to_string()),
+                (4, "not a url".to_string()),
+            ]
+        );
+        let tags: Vec<(i64,)> = sqlx::query_as("select gif_id from gif_tags")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(tags.is_empty());
+    }
+}
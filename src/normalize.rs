@@ -0,0 +1,209 @@
+use std::str::{FromStr, Utf8Error};
+
+use percent_encoding::percent_decode_str;
+use unicode_normalization::UnicodeNormalization;
+use url::Url;
+
+/// Query parameters that only exist to track who shared a link, wherever
+/// they turn up.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "igshid", "mc_cid", "mc_eid", "ref_src",
+];
+
+/// Parameters that are only tracking on these hosts and their subdomains.
+/// Elsewhere names this short can mean anything.
+const HOST_TRACKING_PARAMS: &[(&str, &[&str])] = &[
+    ("youtube.com", &["si"]),
+    ("youtu.be", &["si"]),
+    ("spotify.com", &["si"]),
+    ("twitter.com", &["ref", "s", "t"]),
+    ("x.com", &["ref", "s", "t"]),
+];
+
+/// Hosts that serve the same files under the same paths as another host,
+/// mapped to the one we store.
+const MIRRORS: &[(&str, &str)] = &[
+    ("media0.giphy.com", "media.giphy.com"),
+    ("media1.giphy.com", "media.giphy.com"),
+    ("media2.giphy.com", "media.giphy.com"),
+    ("media3.giphy.com", "media.giphy.com"),
+    ("media4.giphy.com", "media.giphy.com"),
+    ("c.tenor.com", "media.tenor.com"),
+];
+
+fn is_tracking(host: &str, param: &str) -> bool {
+    param.starts_with("utm_")
+        || TRACKING_PARAMS.contains(&param)
+        || HOST_TRACKING_PARAMS.iter().any(|(tracked, params)| {
+            (host == *tracked || host.ends_with(&format!(".{}", tracked)))
+                && params.contains(&param)
+        })
+}
+
+/// Rewrites `url` so that links to the same file compare equal: tracking
+/// parameters and the fragment are dropped, and known mirrors are mapped
+/// to one host. `Url` already lowercases the host and drops default ports.
+///
+/// The rest of the query is kept exactly as it was sent, since signed urls
+/// stop working if it's encoded any differently.
+pub fn url(mut url: Url) -> Url {
+    let host = url.host_str().unwrap_or_default().to_string();
+    let query = url.query().map(|query| {
+        query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                let key = percent_decode_str(key).decode_utf8_lossy().to_lowercase();
+                !is_tracking(&host, &key)
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    });
+    url.set_query(query.as_deref().filter(|query| !query.is_empty()));
+    url.set_fragment(None);
+
+    let mirror = MIRRORS
+        .iter()
+        .find(|(mirror, _)| *mirror == host)
+        .map(|(_, canonical)| *canonical);
+    if let Some(canonical) = mirror {
+        // Only fails for hosts that can't be parsed, and these all can
+        url.set_host(Some(canonical)).ok();
+    }
+
+    url
+}
+
+/// The form categories are stored and looked up in: compatibility
+/// composed (NFKC), case folded, trimmed, and with every run of whitespace
+/// made a single space. So `Hug`, ` hug ` and `ｈｕｇ` are all `hug`, and
+/// `STRASSE` and `straße` are both `strasse`.
+pub fn category(category: &str) -> String {
+    let lower = category.nfkc().collect::<String>().to_lowercase();
+    let mut folded = String::with_capacity(lower.len());
+    for c in lower.nfd() {
+        fold(c, &mut folded);
+    }
+    let folded = folded.nfkc().collect::<String>();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Full case folding is lowercasing plus these, once NFKC has dealt with
+// ligatures and compatibility letters and the text is decomposed, so an
+// iota subscript is a character of its own.
+fn fold(c: char, folded: &mut String) {
+    match c {
+        'ß' => folded.push_str("ss"),
+        'ς' => folded.push('σ'),
+        '\u{345}' => folded.push('ι'),
+        // Old Cyrillic letter forms
+        '\u{1c80}' => folded.push('в'),
+        '\u{1c81}' => folded.push('д'),
+        '\u{1c82}' => folded.push('о'),
+        '\u{1c83}' => folded.push('с'),
+        '\u{1c84}' | '\u{1c85}' => folded.push('т'),
+        '\u{1c86}' => folded.push('ъ'),
+        '\u{1c87}' => folded.push('ѣ'),
+        '\u{1c88}' => folded.push('ꙋ'),
+        // Cherokee folds to its uppercase letters, which lowercasing just
+        // moved away from
+        '\u{ab70}'..='\u{abbf}' | '\u{13f8}'..='\u{13fd}' => {
+            let offset = if c >= '\u{ab70}' { 0x97d0 } else { 8 };
+            folded.extend(std::char::from_u32(c as u32 - offset));
+        }
+        c => folded.push(c),
+    }
+}
+
+/// A path segment with its percent-encoding undone, for `warp::path!` in
+/// place of `String`, which hands over the segment as sent.
+#[derive(Debug)]
+pub struct Decoded(pub String);
+
+impl FromStr for Decoded {
+    type Err = Utf8Error;
+
+    fn from_str(segment: &str) -> Result<Decoded, Utf8Error> {
+        let decoded = percent_decode_str(segment).decode_utf8()?;
+        Ok(Decoded(decoded.into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> String {
+        super::url(Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn mirrors_map_to_one_host() {
+        assert_eq!(
+            normalized("https://media3.giphy.com/media/abc/giphy.gif"),
+            "https://media.giphy.com/media/abc/giphy.gif"
+        );
+        assert_eq!(
+            normalized("https://c.tenor.com/abc/hug.gif"),
+            "https://media.tenor.com/abc/hug.gif"
+        );
+    }
+
+    #[test]
+    fn tracking_params_and_fragment_are_dropped() {
+        assert_eq!(
+            normalized("https://media.tenor.com/a.gif?utm_source=x&id=5&FBCLID=1#top"),
+            "https://media.tenor.com/a.gif?id=5"
+        );
+        assert_eq!(
+            normalized("https://media.tenor.com/a.gif?utm_medium=share&gclid=2"),
+            "https://media.tenor.com/a.gif"
+        );
+    }
+
+    #[test]
+    fn short_params_are_only_tracking_on_their_hosts() {
+        assert_eq!(
+            normalized("https://www.youtube.com/a.gif?v=1&si=abc"),
+            "https://www.youtube.com/a.gif?v=1"
+        );
+        assert_eq!(
+            normalized("https://cdn.example.com/a.gif?si=abc&ref=main"),
+            "https://cdn.example.com/a.gif?si=abc&ref=main"
+        );
+    }
+
+    #[test]
+    fn categories_are_case_folded() {
+        assert_eq!(category("  Hug\t\n Time "), "hug time");
+        assert_eq!(category("ｈｕｇ"), "hug");
+        assert_eq!(category("STRASSE"), "strasse");
+        assert_eq!(category("straße"), category("STRAẞE"));
+        // Final sigma folds to the same sigma as the rest
+        assert_eq!(category("ΟΔΥΣΣΕΥΣ"), "οδυσσευσ");
+        assert_eq!(category("οδυσσευς"), "οδυσσευσ");
+        assert_eq!(category("ᾼ"), category("ΑΙ"));
+        assert_eq!(category("\u{1c80}"), "в");
+        assert_eq!(category("Ꭰ"), category("\u{ab70}"));
+        assert_eq!(category("ﬁle"), "file");
+        assert_eq!(category(" \u{3000} "), "");
+    }
+
+    #[test]
+    fn decoded_undoes_percent_encoding() {
+        let decoded = |segment: &str| segment.parse::<Decoded>().map(|Decoded(s)| s);
+        assert_eq!(decoded("hug").unwrap(), "hug");
+        assert_eq!(decoded("caf%C3%A9").unwrap(), "café");
+        assert_eq!(decoded("hug%20time").unwrap(), "hug time");
+        assert_eq!(decoded("a%2Fb").unwrap(), "a/b");
+        // `+` only means a space in query strings
+        assert_eq!(decoded("a+b").unwrap(), "a+b");
+        assert!(decoded("%FF").is_err());
+    }
+
+    #[test]
+    fn signed_query_is_left_alone() {
+        let signed = "https://cdn.example.com/a.gif?Expires=1700000000&Key-Pair-Id=K2&Signature=a%2Bb%20c~d_&x=";
+        assert_eq!(normalized(signed), signed);
+    }
+}
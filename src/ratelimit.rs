@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use warp::{reject, Filter, Rejection};
+
+use crate::auth;
+use crate::store::Store;
+
+/// Buckets kept before full ones are swept out. A full bucket is the same
+/// as no bucket, so sweeping never lets anyone through early.
+const SWEEP_AT: usize = 10_000;
+
+/// Least time between sweeps, so a table that stays big isn't scanned on
+/// every request.
+const SWEEP_EVERY: Duration = Duration::from_secs(10);
+
+/// How long a key found in the store is trusted to pick the bucket. It
+/// only decides who a request counts against; `auth::require` still checks
+/// the key itself.
+const KEY_TTL: Duration = Duration::from_secs(60);
+
+/// Which limit a route counts against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    Read,
+    Write,
+}
+
+/// The client used up its bucket. It can try again in `retry_after` seconds.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: u64,
+}
+
+impl reject::Reject for RateLimited {}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+    buckets: HashMap<(Class, String), Bucket>,
+    /// Hashes of keys found in the store, with the key's id and when it was
+    /// looked up.
+    keys: HashMap<String, (i64, Instant)>,
+    swept: Instant,
+}
+
+/// Token buckets per client and route class.
+///
+/// Each bucket holds up to a minute's worth of requests and refills
+/// continuously, so a client can burst up to its limit and then gets a
+/// steady share of it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Requests per minute for read routes, 0 for no limit.
+    pub read: u32,
+    /// Requests per minute for write routes, 0 for no limit.
+    pub write: u32,
+    /// Take the client ip from `X-Forwarded-For`, for running behind a proxy.
+    pub trust_proxy: bool,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(read: u32, write: u32, trust_proxy: bool) -> RateLimiter {
+        RateLimiter {
+            read,
+            write,
+            trust_proxy,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                keys: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    fn per_minute(&self, class: Class) -> u32 {
+        match class {
+            Class::Read => self.read,
+            Class::Write => self.write,
+        }
+    }
+
+    /// Takes a token from `client`'s bucket for `class`.
+    pub fn check(&self, class: Class, client: &str) -> Result<(), RateLimited> {
+        let per_minute = self.per_minute(class);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut state = self.state.lock().unwrap();
+        if state.buckets.len() >= SWEEP_AT && now.duration_since(state.swept) >= SWEEP_EVERY {
+            self.sweep(&mut state, now);
+        }
+
+        let bucket = state
+            .buckets
+            .entry((class, client.to_string()))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * per_second)
+            .min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / per_second;
+            Err(RateLimited {
+                retry_after: (wait.ceil() as u64).max(1),
+            })
+        }
+    }
+
+    fn sweep(&self, state: &mut State, now: Instant) {
+        state.buckets.retain(|(class, _), bucket| {
+            let refill = f64::from(self.per_minute(*class)) / 60.0;
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill
+                < f64::from(self.per_minute(*class))
+        });
+        state
+            .keys
+            .retain(|_, (_, found)| now.duration_since(*found) < KEY_TTL);
+        state.swept = now;
+    }
+
+    /// The id of the key with `key_hash`, if it was found in the store
+    /// recently.
+    fn known_key(&self, key_hash: &str) -> Option<i64> {
+        let state = self.state.lock().unwrap();
+        state
+            .keys
+            .get(key_hash)
+            .filter(|(_, found)| found.elapsed() < KEY_TTL)
+            .map(|(id, _)| *id)
+    }
+
+    fn remember_key(&self, key_hash: String, id: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.keys.insert(key_hash, (id, Instant::now()));
+    }
+
+    /// The client's ip, from `X-Forwarded-For` if we're told to trust it.
+    fn ip(&self, forwarded_for: Option<String>, remote: Option<SocketAddr>) -> String {
+        let forwarded = forwarded_for
+            .filter(|_| self.trust_proxy)
+            .and_then(|header| header.split(',').next()?.trim().parse::<IpAddr>().ok());
+        let ip = forwarded.or_else(|| remote.map(|addr| addr.ip()));
+        match ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "unknown".into(),
+        }
+    }
+}
+
+/// Rejects with `RateLimited` once the client is over its `class` limit.
+///
+/// A client is its API key when it sends a valid one, otherwise its ip.
+/// Keys the limiter hasn't seen lately cost their ip a token before the
+/// store is asked about them, so made up keys can't flood the database.
+pub fn limit(
+    class: Class,
+    limiter: Arc<RateLimiter>,
+    store: Store,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::addr::remote())
+        .and(warp::any().map(move || limiter.clone()))
+        .and(warp::any().map(move || store.clone()))
+        .and_then(
+            move |authorization: Option<String>,
+                  forwarded_for: Option<String>,
+                  remote: Option<SocketAddr>,
+                  limiter: Arc<RateLimiter>,
+                  store: Store| async move {
+                if limiter.per_minute(class) == 0 {
+                    return Ok::<(), Rejection>(());
+                }
+                let ip = limiter.ip(forwarded_for, remote);
+                let key_hash = authorization
+                    .as_deref()
+                    .and_then(auth::bearer)
+                    .map(auth::hash_key);
+
+                let client = match key_hash {
+                    None => ip,
+                    Some(key_hash) => match limiter.known_key(&key_hash) {
+                        Some(id) => format!("key:{}", id),
+                        None => {
+                            limiter.check(class, &ip).map_err(reject::custom)?;
+                            match store.api_key(&key_hash).await? {
+                                Some(api_key) => {
+                                    limiter.remember_key(key_hash, api_key.id);
+                                    format!("key:{}", api_key.id)
+                                }
+                                // Already counted against its ip
+                                None => return Ok(()),
+                            }
+                        }
+                    },
+                };
+                limiter.check(class, &client).map_err(reject::custom)
+            },
+        )
+        .untuple_one()
+}
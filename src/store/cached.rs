@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::{Entry, GifStore, Store};
+use crate::auth::ApiKey;
+use crate::error::Error;
+use crate::{Alias, CategoryCount, Gif, GifPatch, Subcategory};
+
+struct Cached<T> {
+    value: T,
+    loaded: Instant,
+}
+
+impl<T: Clone> Cached<T> {
+    fn new(value: T) -> Cached<T> {
+        Cached {
+            value,
+            loaded: Instant::now(),
+        }
+    }
+
+    fn fresh(&self, ttl: Duration) -> Option<T> {
+        Some(self.value.clone()).filter(|_| self.loaded.elapsed() < ttl)
+    }
+}
+
+/// Wraps another store and keeps the id and weight of every gif in each
+/// category in memory, so a random pick only has to load the gifs it chose.
+/// Aliases and subcategories are kept too, since every pick resolves them.
+///
+/// Writes through this store update the cache straight away. Writes from
+/// other instances show up once what's cached is older than `ttl`.
+pub struct CachedStore {
+    inner: Store,
+    ttl: Duration,
+    entries: RwLock<HashMap<String, Cached<Arc<Vec<Entry>>>>>,
+    /// Bumped by every local write, so a load that was already running
+    /// doesn't put back what the write just dropped.
+    generation: AtomicU64,
+    aliases: RwLock<Option<Cached<Vec<Alias>>>>,
+    subcategories: RwLock<Option<Cached<Vec<Subcategory>>>>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Store, ttl: Duration) -> CachedStore {
+        CachedStore {
+            inner,
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            aliases: RwLock::new(None),
+            subcategories: RwLock::new(None),
+        }
+    }
+
+    /// Drops `id` from whichever category has it and forgets `category`, if
+    /// given, so it's reloaded with whatever changed.
+    fn invalidate(&self, id: i64, category: Option<&str>) {
+        let mut cache = self.entries.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for cached in cache.values_mut() {
+            if cached
+                .value
+                .binary_search_by_key(&id, |entry| entry.id)
+                .is_ok()
+            {
+                let kept = cached.value.iter().filter(|entry| entry.id != id);
+                cached.value = Arc::new(kept.copied().collect());
+            }
+        }
+        if let Some(category) = category {
+            cache.remove(category);
+        }
+    }
+}
+
+#[async_trait]
+impl GifStore for CachedStore {
+    async fn entries(&self, category: &str) -> Result<Arc<Vec<Entry>>, Error> {
+        let cached = self
+            .entries
+            .read()
+            .unwrap()
+            .get(category)
+            .and_then(|cached| cached.fresh(self.ttl));
+        if let Some(entries) = cached {
+            return Ok(entries);
+        }
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        let entries = self.inner.entries(category).await?;
+        let mut cache = self.entries.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            cache.insert(category.to_string(), Cached::new(entries.clone()));
+        }
+        Ok(entries)
+    }
+
+    fn forget(&self, ids: &[i64]) {
+        for id in ids {
+            self.invalidate(*id, None);
+        }
+    }
+
+    async fn get_many(&self, ids: &[i64]) -> Result<Vec<Gif>, Error> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn tagged(&self, category: &str, tags: &[String], all: bool) -> Result<Vec<i64>, Error> {
+        self.inner.tagged(category, tags, all).await
+    }
+
+    async fn insert(&self, gif: Gif) -> Result<Gif, Error> {
+        let gif = self.inner.insert(gif).await?;
+        self.invalidate(gif.id, Some(&gif.category));
+        Ok(gif)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Gif>, Error> {
+        self.inner.get(id).await
+    }
+
+    async fn find_by_url(&self, category: &str, url: &str) -> Result<Option<Gif>, Error> {
+        self.inner.find_by_url(category, url).await
+    }
+
+    async fn update(&self, id: i64, patch: &GifPatch) -> Result<Option<Gif>, Error> {
+        let gif = self.inner.update(id, patch).await?;
+        if let Some(gif) = &gif {
+            self.invalidate(id, Some(&gif.category));
+        }
+        Ok(gif)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool, Error> {
+        let deleted = self.inner.delete(id).await?;
+        if deleted {
+            self.invalidate(id, None);
+        }
+        Ok(deleted)
+    }
+
+    async fn list(
+        &self,
+        after: Option<i64>,
+        category: Option<&str>,
+        url: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Gif>, Error> {
+        self.inner.list(after, category, url, limit).await
+    }
+
+    async fn categories(&self) -> Result<Vec<CategoryCount>, Error> {
+        self.inner.categories().await
+    }
+
+    async fn aliases(&self) -> Result<Vec<Alias>, Error> {
+        let cached = self
+            .aliases
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|cached| cached.fresh(self.ttl));
+        if let Some(aliases) = cached {
+            return Ok(aliases);
+        }
+
+        let aliases = self.inner.aliases().await?;
+        *self.aliases.write().unwrap() = Some(Cached::new(aliases.clone()));
+        Ok(aliases)
+    }
+
+    async fn set_alias(&self, alias: Alias) -> Result<Alias, Error> {
+        let alias = self.inner.set_alias(alias).await?;
+        *self.aliases.write().unwrap() = None;
+        Ok(alias)
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<bool, Error> {
+        let deleted = self.inner.delete_alias(alias).await?;
+        *self.aliases.write().unwrap() = None;
+        Ok(deleted)
+    }
+
+    async fn subcategories(&self) -> Result<Vec<Subcategory>, Error> {
+        let cached = self
+            .subcategories
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|cached| cached.fresh(self.ttl));
+        if let Some(subcategories) = cached {
+            return Ok(subcategories);
+        }
+
+        let subcategories = self.inner.subcategories().await?;
+        *self.subcategories.write().unwrap() = Some(Cached::new(subcategories.clone()));
+        Ok(subcategories)
+    }
+
+    async fn add_subcategory(&self, link: Subcategory) -> Result<Subcategory, Error> {
+        let link = self.inner.add_subcategory(link).await?;
+        *self.subcategories.write().unwrap() = None;
+        Ok(link)
+    }
+
+    async fn remove_subcategory(&self, parent: &str, child: &str) -> Result<bool, Error> {
+        let removed = self.inner.remove_subcategory(parent, child).await?;
+        *self.subcategories.write().unwrap() = None;
+        Ok(removed)
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        self.inner.api_key(key_hash).await
+    }
+
+    async fn insert_api_key(&self, key: ApiKey) -> Result<ApiKey, Error> {
+        self.inner.insert_api_key(key).await
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        self.inner.list_api_keys().await
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, Error> {
+        self.inner.revoke_api_key(id).await
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use super::{Entry, GifStore};
+use crate::auth::ApiKey;
+use crate::error::Error;
+use crate::{Alias, CategoryCount, Gif, GifPatch, Subcategory};
+
+/// Keeps gifs in process memory. Everything is gone on restart, which is
+/// exactly what tests and quick local runs want.
+#[derive(Default)]
+pub struct MemoryStore {
+    gifs: RwLock<BTreeMap<i64, Gif>>,
+    api_keys: RwLock<BTreeMap<i64, ApiKey>>,
+    aliases: RwLock<BTreeMap<String, String>>,
+    subcategories: RwLock<BTreeSet<(String, String)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+// Stands in for the `(category, url)` unique index the SQL stores have
+fn duplicate(gifs: &BTreeMap<i64, Gif>, id: i64, category: &str, url: &str) -> Result<(), Error> {
+    let taken = gifs
+        .values()
+        .any(|gif| gif.id != id && gif.category == category && gif.url == url);
+    if taken {
+        let err = sqlx::Error::Protocol("duplicate url in category".into());
+        return Err(Error::Conflict(err));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl GifStore for MemoryStore {
+    async fn entries(&self, category: &str) -> Result<Arc<Vec<Entry>>, Error> {
+        let gifs = self.gifs.read().unwrap();
+        Ok(Arc::new(
+            gifs.values()
+                .filter(|gif| gif.category == category)
+                .map(|gif| Entry {
+                    id: gif.id,
+                    weight: gif.weight,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn get_many(&self, ids: &[i64]) -> Result<Vec<Gif>, Error> {
+        let gifs = self.gifs.read().unwrap();
+        Ok(ids.iter().filter_map(|id| gifs.get(id)).cloned().collect())
+    }
+
+    async fn tagged(&self, category: &str, tags: &[String], all: bool) -> Result<Vec<i64>, Error> {
+        let gifs = self.gifs.read().unwrap();
+        Ok(gifs
+            .values()
+            .filter(|gif| gif.category == category)
+            .filter(|gif| {
+                if all {
+                    tags.iter().all(|tag| gif.tags.contains(tag))
+                } else {
+                    tags.iter().any(|tag| gif.tags.contains(tag))
+                }
+            })
+            .map(|gif| gif.id)
+            .collect())
+    }
+
+    async fn insert(&self, gif: Gif) -> Result<Gif, Error> {
+        let mut gifs = self.gifs.write().unwrap();
+        // Same as the primary key on the SQL stores
+        if gifs.contains_key(&gif.id) {
+            let err = sqlx::Error::Protocol("duplicate gif id".into());
+            return Err(Error::Conflict(err));
+        }
+        duplicate(&gifs, gif.id, &gif.category, &gif.url)?;
+        gifs.insert(gif.id, gif.clone());
+        Ok(gif)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Gif>, Error> {
+        Ok(self.gifs.read().unwrap().get(&id).cloned())
+    }
+
+    async fn find_by_url(&self, category: &str, url: &str) -> Result<Option<Gif>, Error> {
+        let gifs = self.gifs.read().unwrap();
+        Ok(gifs
+            .values()
+            .find(|gif| gif.category == category && gif.url == url)
+            .cloned())
+    }
+
+    async fn update(&self, id: i64, patch: &GifPatch) -> Result<Option<Gif>, Error> {
+        let mut gifs = self.gifs.write().unwrap();
+        if let Some(gif) = gifs.get(&id) {
+            let url = patch.url.as_ref().unwrap_or(&gif.url);
+            let category = patch.category.as_ref().unwrap_or(&gif.category);
+            duplicate(&gifs, id, category, url)?;
+        }
+        Ok(gifs.get_mut(&id).map(|gif| {
+            if let Some(url) = &patch.url {
+                gif.url = url.clone();
+            }
+            if let Some(category) = &patch.category {
+                gif.category = category.clone();
+            }
+            if let Some(weight) = patch.weight {
+                gif.weight = weight;
+            }
+            if let Some(tags) = &patch.tags {
+                gif.tags = tags.clone();
+            }
+            gif.clone()
+        }))
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool, Error> {
+        Ok(self.gifs.write().unwrap().remove(&id).is_some())
+    }
+
+    async fn list(
+        &self,
+        after: Option<i64>,
+        category: Option<&str>,
+        url: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Gif>, Error> {
+        let url = url.map(str::to_lowercase);
+        let gifs = self.gifs.read().unwrap();
+        Ok(gifs
+            .range(after.map_or(i64::MIN, |after| after.saturating_add(1))..)
+            .map(|(_, gif)| gif)
+            .filter(|gif| category.is_none_or(|category| gif.category == category))
+            .filter(|gif| {
+                url.as_ref()
+                    .is_none_or(|url| gif.url.to_lowercase().contains(url.as_str()))
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn categories(&self) -> Result<Vec<CategoryCount>, Error> {
+        let mut counts = BTreeMap::new();
+        for gif in self.gifs.read().unwrap().values() {
+            *counts.entry(gif.category.clone()).or_insert(0) += 1;
+        }
+        Ok(counts
+            .into_iter()
+            .map(|(category, count)| CategoryCount { category, count })
+            .collect())
+    }
+
+    async fn aliases(&self) -> Result<Vec<Alias>, Error> {
+        let aliases = self.aliases.read().unwrap();
+        Ok(aliases
+            .iter()
+            .map(|(alias, category)| Alias {
+                alias: alias.clone(),
+                category: category.clone(),
+            })
+            .collect())
+    }
+
+    async fn set_alias(&self, alias: Alias) -> Result<Alias, Error> {
+        self.aliases
+            .write()
+            .unwrap()
+            .insert(alias.alias.clone(), alias.category.clone());
+        Ok(alias)
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<bool, Error> {
+        Ok(self.aliases.write().unwrap().remove(alias).is_some())
+    }
+
+    async fn subcategories(&self) -> Result<Vec<Subcategory>, Error> {
+        let subcategories = self.subcategories.read().unwrap();
+        Ok(subcategories
+            .iter()
+            .map(|(parent, child)| Subcategory {
+                parent: parent.clone(),
+                child: child.clone(),
+            })
+            .collect())
+    }
+
+    async fn add_subcategory(&self, link: Subcategory) -> Result<Subcategory, Error> {
+        self.subcategories
+            .write()
+            .unwrap()
+            .insert((link.parent.clone(), link.child.clone()));
+        Ok(link)
+    }
+
+    async fn remove_subcategory(&self, parent: &str, child: &str) -> Result<bool, Error> {
+        let link = (parent.to_string(), child.to_string());
+        Ok(self.subcategories.write().unwrap().remove(&link))
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        let api_keys = self.api_keys.read().unwrap();
+        Ok(api_keys
+            .values()
+            .find(|key| key.key_hash == key_hash)
+            .cloned())
+    }
+
+    async fn insert_api_key(&self, key: ApiKey) -> Result<ApiKey, Error> {
+        self.api_keys.write().unwrap().insert(key.id, key.clone());
+        Ok(key)
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        Ok(self.api_keys.read().unwrap().values().cloned().collect())
+    }
+
+    // Revoked keys are useless, so there's no point keeping them around here
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, Error> {
+        Ok(self.api_keys.write().unwrap().remove(&id).is_some())
+    }
+}
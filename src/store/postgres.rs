@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::Done;
+use sqlx::postgres::PgPool;
+
+use super::{ApiKeyRow, Entry, GifStore};
+use crate::auth::{self, ApiKey};
+use crate::error::Error;
+use crate::{Alias, CategoryCount, Gif, GifPatch, Subcategory};
+
+// Selects every `Gif` column, with tags gathered into an array.
+const SELECT_GIF: &str = "
+    select g.id, g.url, g.category, g.source, g.weight,
+        array(select t.tag from gif_tags t where t.gif_id = g.id order by t.tag) as tags
+    from gif_gifs g
+    ";
+
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> PgStore {
+        PgStore { pool }
+    }
+}
+
+#[async_trait]
+impl GifStore for PgStore {
+    async fn entries(&self, category: &str) -> Result<Arc<Vec<Entry>>, Error> {
+        let entries = sqlx::query_as::<_, Entry>(
+            "select id, weight from gif_gifs where category = $1 order by id",
+        )
+        .bind(category)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Arc::new(entries))
+    }
+
+    async fn get_many(&self, ids: &[i64]) -> Result<Vec<Gif>, Error> {
+        let query = format!("{} where g.id = any($1)", SELECT_GIF);
+        sqlx::query_as::<_, Gif>(&query)
+            .bind(ids)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn insert(&self, gif: Gif) -> Result<Gif, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "insert into gif_gifs (id, url, category, source, weight) values ($1, $2, $3, $4, $5)",
+        )
+        .bind(gif.id)
+        .bind(&gif.url)
+        .bind(&gif.category)
+        .bind(&gif.source)
+        .bind(gif.weight)
+        .execute(&mut tx)
+        .await?;
+        for tag in &gif.tags {
+            sqlx::query("insert into gif_tags (gif_id, tag) values ($1, $2)")
+                .bind(gif.id)
+                .bind(tag)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(gif)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Gif>, Error> {
+        let query = format!("{} where g.id = $1", SELECT_GIF);
+        sqlx::query_as::<_, Gif>(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn find_by_url(&self, category: &str, url: &str) -> Result<Option<Gif>, Error> {
+        let query = format!("{} where g.category = $1 and g.url = $2", SELECT_GIF);
+        sqlx::query_as::<_, Gif>(&query)
+            .bind(category)
+            .bind(url)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn tagged(&self, category: &str, tags: &[String], all: bool) -> Result<Vec<i64>, Error> {
+        let ids = sqlx::query_as::<_, (i64,)>(
+            "
+            select g.id
+            from gif_gifs g
+            join gif_tags t on t.gif_id = g.id
+            where g.category = $1 and t.tag = any($2)
+            group by g.id
+            having count(*) >= $3
+            order by g.id
+            ",
+        )
+        .bind(category)
+        .bind(tags)
+        .bind(if all { tags.len() as i64 } else { 1 })
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn update(&self, id: i64, patch: &GifPatch) -> Result<Option<Gif>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let done = sqlx::query(
+            "
+            update gif_gifs
+            set url = coalesce($2, url),
+                category = coalesce($3, category),
+                weight = coalesce($4, weight)
+            where id = $1
+            ",
+        )
+        .bind(id)
+        .bind(&patch.url)
+        .bind(&patch.category)
+        .bind(patch.weight)
+        .execute(&mut tx)
+        .await?;
+        if done.rows_affected() == 0 {
+            return Ok(None);
+        }
+        if let Some(tags) = &patch.tags {
+            sqlx::query("delete from gif_tags where gif_id = $1")
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            for tag in tags {
+                sqlx::query("insert into gif_tags (gif_id, tag) values ($1, $2)")
+                    .bind(id)
+                    .bind(tag)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.get(id).await
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool, Error> {
+        let done = sqlx::query("delete from gif_gifs where id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn list(
+        &self,
+        after: Option<i64>,
+        category: Option<&str>,
+        url: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Gif>, Error> {
+        let query = format!(
+            "
+            {}
+            where ($1::bigint is null or g.id > $1)
+                and ($2::text is null or g.category = $2)
+                and ($3::text is null or strpos(lower(g.url), lower($3)) > 0)
+            order by g.id
+            limit $4
+            ",
+            SELECT_GIF
+        );
+        sqlx::query_as::<_, Gif>(&query)
+            .bind(after)
+            .bind(category)
+            .bind(url)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn categories(&self) -> Result<Vec<CategoryCount>, Error> {
+        sqlx::query_as::<_, CategoryCount>(
+            "
+            select category, count(*) as count
+            from gif_gifs
+            group by category
+            order by category
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn aliases(&self) -> Result<Vec<Alias>, Error> {
+        sqlx::query_as::<_, Alias>(
+            "select alias, category from gif_category_aliases order by alias",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn set_alias(&self, alias: Alias) -> Result<Alias, Error> {
+        sqlx::query(
+            "
+            insert into gif_category_aliases (alias, category) values ($1, $2)
+            on conflict (alias) do update set category = excluded.category
+            ",
+        )
+        .bind(&alias.alias)
+        .bind(&alias.category)
+        .execute(&self.pool)
+        .await?;
+        Ok(alias)
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<bool, Error> {
+        let done = sqlx::query("delete from gif_category_aliases where alias = $1")
+            .bind(alias)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn subcategories(&self) -> Result<Vec<Subcategory>, Error> {
+        sqlx::query_as::<_, Subcategory>(
+            "select parent, child from gif_subcategories order by parent, child",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn add_subcategory(&self, link: Subcategory) -> Result<Subcategory, Error> {
+        sqlx::query(
+            "
+            insert into gif_subcategories (parent, child) values ($1, $2)
+            on conflict (parent, child) do nothing
+            ",
+        )
+        .bind(&link.parent)
+        .bind(&link.child)
+        .execute(&self.pool)
+        .await?;
+        Ok(link)
+    }
+
+    async fn remove_subcategory(&self, parent: &str, child: &str) -> Result<bool, Error> {
+        let done = sqlx::query("delete from gif_subcategories where parent = $1 and child = $2")
+            .bind(parent)
+            .bind(child)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        let row = sqlx::query_as::<_, ApiKeyRow>(
+            "
+            select id, name, key_hash, scopes, created_at
+            from gif_api_keys
+            where key_hash = $1 and revoked_at is null
+            ",
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(ApiKey::from))
+    }
+
+    async fn insert_api_key(&self, key: ApiKey) -> Result<ApiKey, Error> {
+        sqlx::query(
+            "
+            insert into gif_api_keys (id, name, key_hash, scopes, created_at)
+            values ($1, $2, $3, $4, $5)
+            ",
+        )
+        .bind(key.id)
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(auth::format_scopes(&key.scopes))
+        .bind(key.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        let rows = sqlx::query_as::<_, ApiKeyRow>(
+            "
+            select id, name, key_hash, scopes, created_at
+            from gif_api_keys
+            where revoked_at is null
+            order by id
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(ApiKey::from).collect())
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, Error> {
+        let done = sqlx::query(
+            "update gif_api_keys set revoked_at = $1 where id = $2 and revoked_at is null",
+        )
+        .bind(Utc::now().timestamp_millis())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(done.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::Done;
+use sqlx::sqlite::SqlitePool;
+
+use super::{ApiKeyRow, Entry, GifStore};
+use crate::auth::{self, ApiKey};
+use crate::error::Error;
+use crate::{Alias, CategoryCount, Gif, GifPatch, Subcategory};
+
+// SQLite has no arrays, so tags come back joined by the ASCII unit separator.
+const TAG_SEPARATOR: char = '\u{1f}';
+
+// Selects every `Gif` column, with tags gathered into one string.
+const SELECT_GIF: &str = "
+    select g.id, g.url, g.category, g.source, g.weight,
+        (select group_concat(t.tag, char(31)) from gif_tags t where t.gif_id = g.id) as tags
+    from gif_gifs g
+    ";
+
+#[derive(sqlx::FromRow)]
+struct GifRow {
+    id: i64,
+    url: String,
+    category: String,
+    source: Option<String>,
+    weight: f64,
+    tags: Option<String>,
+}
+
+impl From<GifRow> for Gif {
+    fn from(row: GifRow) -> Gif {
+        let mut tags: Vec<String> = row
+            .tags
+            .map(|tags| tags.split(TAG_SEPARATOR).map(String::from).collect())
+            .unwrap_or_default();
+        tags.sort();
+        Gif {
+            id: row.id,
+            url: row.url,
+            category: row.category,
+            source: row.source,
+            weight: row.weight,
+            tags,
+        }
+    }
+}
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub fn new(pool: SqlitePool) -> SqliteStore {
+        SqliteStore { pool }
+    }
+}
+
+#[async_trait]
+impl GifStore for SqliteStore {
+    async fn entries(&self, category: &str) -> Result<Arc<Vec<Entry>>, Error> {
+        let entries = sqlx::query_as::<_, Entry>(
+            "select id, weight from gif_gifs where category = ? order by id",
+        )
+        .bind(category)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Arc::new(entries))
+    }
+
+    async fn get_many(&self, ids: &[i64]) -> Result<Vec<Gif>, Error> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        // No array binds in SQLite, so there's one placeholder per id
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let query = format!("{} where g.id in ({})", SELECT_GIF, placeholders);
+        let mut query = sqlx::query_as::<_, GifRow>(&query);
+        for id in ids {
+            query = query.bind(id);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+        Ok(rows.into_iter().map(Gif::from).collect())
+    }
+
+    async fn insert(&self, gif: Gif) -> Result<Gif, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "insert into gif_gifs (id, url, category, source, weight) values (?, ?, ?, ?, ?)",
+        )
+        .bind(gif.id)
+        .bind(&gif.url)
+        .bind(&gif.category)
+        .bind(&gif.source)
+        .bind(gif.weight)
+        .execute(&mut tx)
+        .await?;
+        for tag in &gif.tags {
+            sqlx::query("insert into gif_tags (gif_id, tag) values (?, ?)")
+                .bind(gif.id)
+                .bind(tag)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(gif)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Gif>, Error> {
+        let query = format!("{} where g.id = ?", SELECT_GIF);
+        let row = sqlx::query_as::<_, GifRow>(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(Gif::from))
+    }
+
+    async fn find_by_url(&self, category: &str, url: &str) -> Result<Option<Gif>, Error> {
+        let query = format!("{} where g.category = ? and g.url = ?", SELECT_GIF);
+        let row = sqlx::query_as::<_, GifRow>(&query)
+            .bind(category)
+            .bind(url)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(Gif::from))
+    }
+
+    async fn tagged(&self, category: &str, tags: &[String], all: bool) -> Result<Vec<i64>, Error> {
+        let placeholders = vec!["?"; tags.len()].join(", ");
+        let query = format!(
+            "
+            select g.id
+            from gif_gifs g
+            join gif_tags t on t.gif_id = g.id
+            where g.category = ? and t.tag in ({})
+            group by g.id
+            having count(*) >= ?
+            order by g.id
+            ",
+            placeholders
+        );
+        let mut query = sqlx::query_as::<_, (i64,)>(&query).bind(category);
+        for tag in tags {
+            query = query.bind(tag);
+        }
+        let ids = query
+            .bind(if all { tags.len() as i64 } else { 1 })
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn update(&self, id: i64, patch: &GifPatch) -> Result<Option<Gif>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let done = sqlx::query(
+            "
+            update gif_gifs
+            set url = coalesce(?, url),
+                category = coalesce(?, category),
+                weight = coalesce(?, weight)
+            where id = ?
+            ",
+        )
+        .bind(&patch.url)
+        .bind(&patch.category)
+        .bind(patch.weight)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        if done.rows_affected() == 0 {
+            return Ok(None);
+        }
+        if let Some(tags) = &patch.tags {
+            sqlx::query("delete from gif_tags where gif_id = ?")
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            for tag in tags {
+                sqlx::query("insert into gif_tags (gif_id, tag) values (?, ?)")
+                    .bind(id)
+                    .bind(tag)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.get(id).await
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool, Error> {
+        // Foreign keys may be off for this connection, so don't count on the cascade
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("delete from gif_tags where gif_id = ?")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let done = sqlx::query("delete from gif_gifs where id = ?")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn list(
+        &self,
+        after: Option<i64>,
+        category: Option<&str>,
+        url: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Gif>, Error> {
+        let query = format!(
+            "
+            {}
+            where (?1 is null or g.id > ?1)
+                and (?2 is null or g.category = ?2)
+                and (?3 is null or instr(lower(g.url), lower(?3)) > 0)
+            order by g.id
+            limit ?4
+            ",
+            SELECT_GIF
+        );
+        let rows = sqlx::query_as::<_, GifRow>(&query)
+            .bind(after)
+            .bind(category)
+            .bind(url)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(Gif::from).collect())
+    }
+
+    async fn categories(&self) -> Result<Vec<CategoryCount>, Error> {
+        sqlx::query_as::<_, CategoryCount>(
+            "
+            select category, count(*) as count
+            from gif_gifs
+            group by category
+            order by category
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn aliases(&self) -> Result<Vec<Alias>, Error> {
+        sqlx::query_as::<_, Alias>(
+            "select alias, category from gif_category_aliases order by alias",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn set_alias(&self, alias: Alias) -> Result<Alias, Error> {
+        sqlx::query(
+            "
+            insert into gif_category_aliases (alias, category) values (?, ?)
+            on conflict (alias) do update set category = excluded.category
+            ",
+        )
+        .bind(&alias.alias)
+        .bind(&alias.category)
+        .execute(&self.pool)
+        .await?;
+        Ok(alias)
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<bool, Error> {
+        let done = sqlx::query("delete from gif_category_aliases where alias = ?")
+            .bind(alias)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn subcategories(&self) -> Result<Vec<Subcategory>, Error> {
+        sqlx::query_as::<_, Subcategory>(
+            "select parent, child from gif_subcategories order by parent, child",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn add_subcategory(&self, link: Subcategory) -> Result<Subcategory, Error> {
+        sqlx::query(
+            "
+            insert into gif_subcategories (parent, child) values (?, ?)
+            on conflict (parent, child) do nothing
+            ",
+        )
+        .bind(&link.parent)
+        .bind(&link.child)
+        .execute(&self.pool)
+        .await?;
+        Ok(link)
+    }
+
+    async fn remove_subcategory(&self, parent: &str, child: &str) -> Result<bool, Error> {
+        let done = sqlx::query("delete from gif_subcategories where parent = ? and child = ?")
+            .bind(parent)
+            .bind(child)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() > 0)
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        let row = sqlx::query_as::<_, ApiKeyRow>(
+            "
+            select id, name, key_hash, scopes, created_at
+            from gif_api_keys
+            where key_hash = ? and revoked_at is null
+            ",
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(ApiKey::from))
+    }
+
+    async fn insert_api_key(&self, key: ApiKey) -> Result<ApiKey, Error> {
+        sqlx::query(
+            "
+            insert into gif_api_keys (id, name, key_hash, scopes, created_at)
+            values (?, ?, ?, ?, ?)
+            ",
+        )
+        .bind(key.id)
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(auth::format_scopes(&key.scopes))
+        .bind(key.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        let rows = sqlx::query_as::<_, ApiKeyRow>(
+            "
+            select id, name, key_hash, scopes, created_at
+            from gif_api_keys
+            where revoked_at is null
+            order by id
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(ApiKey::from).collect())
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, Error> {
+        let done = sqlx::query(
+            "update gif_api_keys set revoked_at = ? where id = ? and revoked_at is null",
+        )
+        .bind(Utc::now().timestamp_millis())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(done.rows_affected() > 0)
+    }
+}
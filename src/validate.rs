@@ -0,0 +1,68 @@
+use url::Url;
+use warp::reject::Reject;
+
+/// File extensions a gif url may end in.
+const EXTENSIONS: &[&str] = &["gif", "gifv", "webp", "mp4", "webm", "png", "jpg", "jpeg"];
+
+/// Why a submitted url was turned away.
+#[derive(Debug)]
+pub enum InvalidUrl {
+    Malformed,
+    Scheme,
+    Host,
+    Extension,
+}
+
+impl InvalidUrl {
+    /// Machine readable code for `ErrorMessage`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InvalidUrl::Malformed => "URL_MALFORMED",
+            InvalidUrl::Scheme => "URL_SCHEME_NOT_ALLOWED",
+            InvalidUrl::Host => "URL_HOST_NOT_ALLOWED",
+            InvalidUrl::Extension => "URL_NOT_AN_IMAGE",
+        }
+    }
+}
+
+impl Reject for InvalidUrl {}
+
+/// What a gif url has to look like before it's stored.
+#[derive(Debug)]
+pub struct UrlRules {
+    /// Hosts gifs may come from. Subdomains of these are allowed too, and
+    /// an empty list allows every host.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl UrlRules {
+    /// Parses `url` and checks it against the rules.
+    pub fn check(&self, url: &str) -> Result<Url, InvalidUrl> {
+        let url = Url::parse(url.trim()).map_err(|_| InvalidUrl::Malformed)?;
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(InvalidUrl::Scheme);
+        }
+
+        // `Url` lowercases hosts for us
+        let host = url.host_str().ok_or(InvalidUrl::Malformed)?;
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| host == allowed || host.ends_with(&format!(".{}", allowed)))
+        {
+            return Err(InvalidUrl::Host);
+        }
+
+        let extension = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(|file| file.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_lowercase());
+        match extension {
+            Some(extension) if EXTENSIONS.contains(&extension.as_str()) => Ok(url),
+            _ => Err(InvalidUrl::Extension),
+        }
+    }
+}